@@ -0,0 +1,21 @@
+use std::process::Command;
+
+/// Output of running `program` with `args`, or "unknown" if it can't be run
+fn output_of(program: &str, args: &[&str]) -> String {
+	Command::new(program).args(args).output().ok()
+		.filter(|output| output.status.success())
+		.and_then(|output| String::from_utf8(output.stdout).ok())
+		.map(|output| output.trim().to_string())
+		.unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Records what bingimage was built from for --version-info
+fn main() {
+	let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+	println!("cargo:rustc-env=BINGIMAGE_GIT_COMMIT={}", output_of("git", &["rev-parse", "--short", "HEAD"]));
+	println!("cargo:rustc-env=BINGIMAGE_RUSTC_VERSION={}", output_of(&rustc, &["--version"]));
+	println!("cargo:rustc-env=BINGIMAGE_TARGET={}", std::env::var("TARGET").unwrap_or_default());
+	// Only rerun for a new commit, not every change to the source
+	println!("cargo:rerun-if-changed=.git/HEAD");
+	println!("cargo:rerun-if-changed=.git/refs");
+}
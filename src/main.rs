@@ -1,215 +1,3921 @@
 use std::{
+	collections::{ hash_map::Entry, BTreeSet, HashMap },
 	fs::File,
-	path::PathBuf,
-	sync::Arc, io::Write
+	path::{ Path, PathBuf },
+	pin::Pin,
+	future::Future,
+	sync::{ atomic::{ AtomicBool, AtomicU32, Ordering }, Arc, Mutex }, io::Write,
+	time::{ Duration, Instant }
 };
-use clap::{ Error, Arg, Command };
+use clap::{ Arg, ArgMatches, Command, ErrorKind };
+use clap_complete::Shell;
+use fs2::FileExt;
+use serde::{ Deserialize, Deserializer, Serialize, Serializer };
 use serde_json::Value as JsonValue;
+use sha2::{ Digest, Sha256 };
+use ab_glyph::{ Font, FontArc, PxScale, ScaleFont };
+use chrono::{ format::{ Item, StrftimeItems }, DateTime, Local, NaiveDate, NaiveTime };
+use reqwest::{ header::{ self, HeaderMap, HeaderValue }, StatusCode };
+use tokio::sync::Semaphore;
+use zip::{ write::FileOptions, CompressionMethod, ZipWriter };
+
+/// Whether `status!` messages go to stderr, because stdout is taken by --json-out -
+static STATUS_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Print a progress message like `println!`, to stdout unless it's for JSON (see
+/// [STATUS_TO_STDERR]), in which case it goes to stderr so the JSON can be piped by itself
+macro_rules! status {
+	($($arg:tt)*) => {
+		match STATUS_TO_STDERR.load(Ordering::Relaxed) {
+			true => eprintln!($($arg)*),
+			false => println!($($arg)*)
+		}
+	}
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
 	let mut app = Command::new("bingimage")
 		.about("Downloads the Bing image of the day")
+		.subcommand_negates_reqs(true)
+		.subcommand(Command::new("list-markets")
+			.about("List known Bing market codes")
+		)
+		.subcommand(Command::new("completion")
+			.about("Print a shell completion script")
+			.long_about("Print a completion script for the given shell to stdout. See the README for where to put it")
+			.hide(true)
+			.arg(Arg::new("shell")
+				.help("Shell to complete in")
+				.required(true)
+				.possible_values(Shell::possible_values())
+			)
+		)
+		.subcommand(Command::new("info")
+			.about("Print the metadata of today's image")
+			.long_about("Print the title, copyright, date and URL of today's image for each --market, without downloading anything")
+			.arg(Arg::new("raw")
+				.long("raw")
+				.visible_alias("all-fields")
+				.help("Print every field of the metadata")
+				.long_help("Print every field of the image's metadata as the API sent it, for seeing what's available. Fields bingimage knows about (url, urlbase, title, copyright, copyrightlink, startdate, enddate, fullstartdate, hsh and quiz) come first and are marked missing if the API left them out, followed by any others")
+				.takes_value(false)
+			)
+		)
+		.subcommand(Command::new("list-downloaded")
+			.about("List the images already in the -p directories")
+			.long_about("List the images already in the -p directories with their date, resolution and size, going by --output-template and --date-format to read them from the file names. Files named without a date are dated by when they were last modified. Files that don't match the template are left out, and images that can't be decoded or dated are pointed out")
+		)
 		.arg(Arg::new("resolution")
 			.short('r')
 			.help("Image resolution")
-			.long_help("Image resolution, formatted as WIDTHxHEIGHT. ex. 1920x1080\nThis argument can be passed multiple times for as many resolutions as you need")
-			.required(true)
+			.long_help("Image resolution, formatted as WIDTHxHEIGHT. ex. 1920x1080\nThis argument can be passed multiple times, or given a comma-separated list (ex. 1920x1080,1366x768), for as many resolutions as you need")
+			.required_unless_present_any(["check", "only-metadata", "verify-checksum-against", "print-config", "version-info", "redownload-corrupt", "probe-supported-resolutions", "resolution-from-env"])
 			.multiple_occurrences(true)
 			.takes_value(true)
+			.env("BINGIMAGE_RESOLUTION")
 			.multiple_values(false)
 		)
+		.arg(Arg::new("resolution-from-env")
+			.long("resolution-from-env")
+			.value_name("variable")
+			.help("Also download the resolution in this environment variable")
+			.long_help("Also download the resolution in this environment variable, formatted like -r, ex. --resolution-from-env DISPLAY_SIZE with DISPLAY_SIZE=2560x1440. For when whatever starts bingimage knows the screen's resolution, but bingimage doesn't. It's an error if the variable isn't set")
+			.takes_value(true)
+			.env("BINGIMAGE_RESOLUTION_FROM_ENV")
+		)
 		.arg(Arg::new("readme")
 			.short('m')
 			.help("Output README.md")
 			.long_help("Output README.md with title and copyright information")
 			.takes_value(false)
+			.env("BINGIMAGE_README")
+		)
+		.arg(Arg::new("copyright-sidecar")
+			.long("copyright-sidecar")
+			.help("Output a .txt file with the title and copyright next to each image")
+			.long_help("Output a .txt file named after each image, with the title and copyright information on separate lines, for gallery software that expects per-image attribution")
+			.takes_value(false)
+			.env("BINGIMAGE_COPYRIGHT_SIDECAR")
+		)
+		.arg(Arg::new("only-metadata")
+			.long("only-metadata")
+			.help("Only output README.md, without downloading any images")
+			.long_help("Only output README.md with title and copyright information, without downloading any images. -r isn't needed with this")
+			.takes_value(false)
+			.env("BINGIMAGE_ONLY_METADATA")
+			.conflicts_with("resolution")
 		)
 		.arg(Arg::new("path")
 			.short('p')
 			.help("Output directory")
-			.long_help("Directory of the output files")
+			.long_help("Directory of the output files. Can be repeated to write every file to each directory, ex. a wallpaper folder and an archive. Each image is only downloaded once")
+			.takes_value(true)
+			.env("BINGIMAGE_PATH")
+			.required_unless_present_any(["archive", "check", "verify-checksum-against", "print-config", "version-info", "probe-supported-resolutions"])
+			.multiple_values(false)
+			.multiple_occurrences(true)
+		)
+		.arg(Arg::new("no-follow")
+			.long("no-follow")
+			.help("Refuse to write into an output directory that's a symlink")
+			.long_help("Refuse to write into an output directory given with -p that's a symlink, instead of writing to wherever it points")
+			.takes_value(false)
+			.env("BINGIMAGE_NO_FOLLOW")
+		)
+		.arg(Arg::new("output-template")
+			.long("output-template")
+			.value_name("template")
+			.help("Name of the downloaded image files")
+			.long_help("Path of the downloaded image files, relative to the output directory. {resolution} is replaced with the image's resolution, {date} with the date the image is for formatted with --date-format, {year}, {month} and {day} with parts of that date, and {urlhash} with a short hash that's the same for the same image on any day and in any market\n/ separates directories, ex. {year}/{month}/{date}-{resolution}.jpg. See --create-dirs")
+			.takes_value(true)
+			.env("BINGIMAGE_OUTPUT_TEMPLATE")
+			.default_value("{resolution}.jpg")
+		)
+		.arg(Arg::new("normalize-extension")
+			.long("normalize-extension")
+			.help("Always end image file names with .jpg")
+			.long_help("End every image file name with a lowercase .jpg, since that's the format Bing serves, replacing any image extension the --output-template gives instead (ex. .JPG, .jpeg or .png)")
+			.takes_value(false)
+			.env("BINGIMAGE_NORMALIZE_EXTENSION")
+		)
+		.arg(Arg::new("create-dirs")
+			.long("create-dirs")
+			.help("Create directories in --output-template that don't exist yet")
+			.long_help("Create any directories in --output-template that don't exist yet. Without this, images that would go in a missing directory are skipped")
+			.takes_value(false)
+			.env("BINGIMAGE_CREATE_DIRS")
+		)
+		.arg(Arg::new("hardlink-duplicates")
+			.long("hardlink-duplicates")
+			.help("Hard link images that are identical instead of storing copies")
+			.long_help("After downloading, hard link images in the same -p directory that have identical contents, ex. the same image and resolution for two markets that have the same image that day, instead of storing a copy of each. Prints how much space it saved")
+			.takes_value(false)
+			.env("BINGIMAGE_HARDLINK_DUPLICATES")
+			.conflicts_with("archive")
+		)
+		.arg(Arg::new("date-format")
+			.long("date-format")
+			.value_name("format")
+			.help("strftime format of dates in file names and metadata")
+			.long_help("strftime format of the image's date, as used by {date} in --output-template and in the JSON metadata. It has to work as a file name: it can't be empty, . or .., or contain path separators")
+			.takes_value(true)
+			.env("BINGIMAGE_DATE_FORMAT")
+			.default_value("%Y-%m-%d")
+		)
+		.arg(Arg::new("archive")
+			.long("archive")
+			.value_name("path.zip")
+			.help("Write the output files into a zip archive")
+			.long_help("Write the downloaded images and README.md into a zip archive at this path instead of into a directory. Each file is added as soon as it's done downloading")
+			.takes_value(true)
+			.env("BINGIMAGE_ARCHIVE")
+			.conflicts_with("path")
+		)
+		.arg(Arg::new("json-out")
+			.long("json-out")
+			.value_name("path")
+			.help("Output the image metadata as JSON")
+			.long_help("Write the title, copyright and URL of the image as JSON to this file, or to stdout if the path is -. With -, everything else bingimage would print to stdout goes to stderr instead, so the JSON can be piped to something like jq")
+			.takes_value(true)
+			.env("BINGIMAGE_JSON_OUT")
+		)
+		.arg(Arg::new("strip-query")
+			.long("strip-query")
+			.help("Leave the tracking parameters out of the URL in the JSON metadata")
+			.long_help("Leave the tracking parameters (rf, pid) out of the URL in the --json-out metadata, for tidier and more stable URLs. The image's id is kept, since it's needed to find the image. Images are still downloaded from the full URL")
+			.takes_value(false)
+			.env("BINGIMAGE_STRIP_QUERY")
+			.requires("json-out")
+		)
+		.arg(Arg::new("compact-json")
+			.long("compact-json")
+			.help("Output JSON on a single line")
+			.long_help("Output JSON on a single line instead of indented. JSON written to a file is indented by default, JSON written to stdout is always compact")
+			.takes_value(false)
+			.env("BINGIMAGE_COMPACT_JSON")
+		)
+		.arg(Arg::new("save-response")
+			.long("save-response")
+			.value_name("path")
+			.help("Save the API's response to a file")
+			.long_help("Write the metadata response to this file exactly as it was sent, including the fields bingimage doesn't use. When --cache-dir reuses a cached response, the cached copy is written instead")
+			.takes_value(true)
+			.env("BINGIMAGE_SAVE_RESPONSE")
+		)
+		.arg(Arg::new("pretty-response")
+			.long("pretty-response")
+			.help("Indent the response saved with --save-response")
+			.takes_value(false)
+			.env("BINGIMAGE_PRETTY_RESPONSE")
+			.requires("save-response")
+		)
+		.arg(Arg::new("source")
+			.long("source")
+			.value_name("name")
+			.help("Where to get the image from")
+			.long_help("Where to get the image from. bing is Bing's own API. peapix (only when built with the peapix feature) is an archive of Bing's images. Only its latest image is downloaded, not older ones from the archive, and it only has one size of each image: it's downloaded for every resolution. spotlight (only when built with the spotlight feature) is Windows Spotlight's lock screen images. Spotlight returns a batch of images picked for each request rather than an image of the day, so one image is asked for and running twice can get two different ones. It has a single 3840x2160 size and no date, so every resolution gets the same file, named by --output-template with today's date")
+			.takes_value(true)
+			.env("BINGIMAGE_SOURCE")
+			.possible_values(SOURCES)
+			.default_value("bing")
+		)
+		.arg(Arg::new("market")
+			.long("market")
+			.value_name("code")
+			.help("Bing market to get the image for")
+			.long_help("Bing market to get the image for, a language and a region code like en-US. See the list-markets subcommand for known codes\nThis argument can be passed multiple times, in which case each market's files go in a subdirectory named after it")
 			.takes_value(true)
-			.required(true)
+			.env("BINGIMAGE_MARKET")
+			.multiple_occurrences(true)
 			.multiple_values(false)
-			.multiple_occurrences(false)
+		)
+		.arg(Arg::new("title-fallback")
+			.long("title-fallback")
+			.help("Use en-US's title and copyright when a market's are empty")
+			.long_help("When a market's title or copyright is missing or empty, fetch en-US's metadata and use its text instead. The image still comes from the requested market, and the text is only used if en-US has the same image")
+			.takes_value(false)
+			.env("BINGIMAGE_TITLE_FALLBACK")
+		)
+		.arg(Arg::new("jobs")
+			.long("jobs")
+			.value_name("count")
+			.help("Maximum number of images to download at once")
+			.long_help("Maximum number of images to download at once, across all markets and resolutions")
+			.takes_value(true)
+			.env("BINGIMAGE_JOBS")
+			.default_value("4")
+			.validator(at_least_one)
+		)
+		.arg(Arg::new("image-threads")
+			.long("image-threads")
+			.value_name("count")
+			.help("Maximum number of images to decode or encode at once")
+			.long_help("Maximum number of images to decode or encode at once, for the filters and for reading colors. This work runs on its own threads so it doesn't slow down downloads. Defaults to the number of CPUs")
+			.takes_value(true)
+			.env("BINGIMAGE_IMAGE_THREADS")
+			.validator(at_least_one)
+		)
+		.arg(Arg::new("download-order")
+			.long("download-order")
+			.value_name("order")
+			.help("Order to start downloading resolutions in")
+			.long_help("Order to start downloading each market's resolutions in when --jobs doesn't let them all start at once: as-listed keeps the order of -r, smallest starts with the fewest pixels for quick early results, and largest starts with the most so the slowest downloads get the most time")
+			.takes_value(true)
+			.env("BINGIMAGE_DOWNLOAD_ORDER")
+			.possible_values(["as-listed", "smallest", "largest"])
+			.default_value("as-listed")
+		)
+		.arg(Arg::new("exit-on-first-success")
+			.long("exit-on-first-success")
+			.help("Stop once any image has downloaded")
+			.long_help("Stop once any image has downloaded successfully, cancelling the rest. Images still waiting for a --jobs slot are never started, so with --jobs 1 resolutions are tried in the order they're given until one works. With more jobs, whichever finishes first is kept, along with any that were already writing their files. Cancelled downloads don't leave any files behind")
+			.takes_value(false)
+			.env("BINGIMAGE_EXIT_ON_FIRST_SUCCESS")
+		)
+		.arg(Arg::new("fail-fast")
+			.long("fail-fast")
+			.help("Stop at the first image that fails to download")
+			.long_help("Stop at the first image that fails to download or write, cancelling the rest, and exit with 1. Normally every download is tried and failures are only printed, which can mean waiting on many doomed downloads when Bing is down")
+			.takes_value(false)
+			.env("BINGIMAGE_FAIL_FAST")
+			.conflicts_with("exit-on-first-success")
+		)
+		.arg(Arg::new("skip-metadata-on-download-failure")
+			.long("skip-metadata-on-download-failure")
+			.help("Don't write the metadata of images that didn't download")
+			.long_help("Wait for the images to download before writing README.md and --json-out, and skip them for a market if none of its images downloaded, so the metadata never describes images that aren't there")
+			.takes_value(false)
+			.env("BINGIMAGE_SKIP_METADATA_ON_DOWNLOAD_FAILURE")
+		)
+		.arg(Arg::new("ratelimit-global")
+			.long("ratelimit-global")
+			.value_name("bytes/s")
+			.help("Maximum number of bytes to download per second, in total")
+			.long_help("Maximum number of bytes to download per second, shared by every download. However many --jobs are running, together they stay under this limit")
+			.takes_value(true)
+			.env("BINGIMAGE_RATELIMIT_GLOBAL")
+			.validator(at_least_one)
+		)
+		.arg(Arg::new("parallel-markets-limit")
+			.long("parallel-markets-limit")
+			.value_name("count")
+			.help("Maximum number of markets to fetch metadata for at once")
+			.long_help("Maximum number of markets to fetch metadata for at once. Metadata for every market is fetched before any image is downloaded, so this throttles the metadata endpoint while --jobs throttles the image downloads\nex. with --market en-US --market de-DE --market ja-JP -r 1920x1080 -r 1366x768 --parallel-markets-limit 2 --jobs 4, metadata is fetched for two markets and then the third, and then four of the six images download at once")
+			.takes_value(true)
+			.env("BINGIMAGE_PARALLEL_MARKETS_LIMIT")
+			.default_value("2")
+			.validator(at_least_one)
+		)
+		.arg(Arg::new("temp-dir")
+			.long("temp-dir")
+			.value_name("path")
+			.help("Directory for files that are still being written")
+			.long_help("Write files into this directory while they're being written, and move them to the output directory once they're done, instead of writing them next to where they go as hidden .tmp files. Keeps the output directories clean if bingimage is killed. It should be on the same filesystem as the output directories: otherwise files are copied instead, and can be seen half-written")
+			.takes_value(true)
+			.env("BINGIMAGE_TEMP_DIR")
+		)
+		.arg(Arg::new("no-fsync")
+			.long("no-fsync")
+			.help("Don't wait for files to be flushed to disk")
+			.long_help("Don't wait for written files to be flushed to disk. Useful on filesystems where syncing is slow or doesn't work, like some network mounts")
+			.takes_value(false)
+			.env("BINGIMAGE_NO_FSYNC")
+		)
+		.arg(Arg::new("force")
+			.long("force")
+			.help("Overwrite a README.md that wasn't written by bingimage")
+			.long_help("Overwrite an existing README.md in the output directory even if it doesn't look like it was written by bingimage")
+			.takes_value(false)
+			.env("BINGIMAGE_FORCE")
+		)
+		.arg(Arg::new("startup-grace")
+			.long("startup-grace")
+			.value_name("seconds")
+			.help("Keep retrying while the network is unreachable")
+			.long_help("Number of seconds to keep retrying requests that fail because the network isn't reachable yet (DNS lookup failures, refused connections), which is common right after boot or resume")
+			.takes_value(true)
+			.env("BINGIMAGE_STARTUP_GRACE")
+			.default_value("0")
+		)
+		.arg(Arg::new("retries")
+			.long("retries")
+			.value_name("count")
+			.help("Number of times to retry a failed request")
+			.long_help("Number of times to retry each request that fails or gets a server error (5xx), waiting a random time up to a limit that doubles with every retry")
+			.takes_value(true)
+			.env("BINGIMAGE_RETRIES")
+			.default_value("0")
+		)
+		.arg(Arg::new("retry-budget")
+			.long("retry-budget")
+			.value_name("count")
+			.help("Maximum number of retries in total")
+			.long_help("Maximum number of retries across every request, on top of --retries for each one. Once it's used up, failed requests give up straight away, so an outage doesn't cause a flood of retries that were never going to work")
+			.takes_value(true)
+			.env("BINGIMAGE_RETRY_BUDGET")
+		)
+		.arg(Arg::new("retry-on-partial")
+			.long("retry-on-partial")
+			.help("Download images again if they're cut off")
+			.long_help("Download an image again if less of it arrives than its Content-Length said there would be, which usually means the connection dropped partway through. Images are retried up to --retries times, or once if --retries is 0, and count towards --retry-budget. Does nothing for servers that don't send a Content-Length")
+			.takes_value(false)
+			.env("BINGIMAGE_RETRY_ON_PARTIAL")
+		)
+		.arg(Arg::new("head-check")
+			.long("head-check")
+			.help("Check that each image exists before downloading it")
+			.long_help("Send a HEAD request for each image before downloading it, and skip resolutions that aren't found or are too small to be an actual image. Saves bandwidth when trying resolutions that might not exist. Servers that don't support HEAD just get the normal download")
+			.takes_value(false)
+			.env("BINGIMAGE_HEAD_CHECK")
+		)
+		.arg(Arg::new("connect-timeout")
+			.long("connect-timeout")
+			.value_name("seconds")
+			.help("Give up on connecting to a server after this long")
+			.long_help("Number of seconds to wait for a connection to be established before giving up on a request. Doesn't limit how long the download itself takes")
+			.takes_value(true)
+			.env("BINGIMAGE_CONNECT_TIMEOUT")
+		)
+		.arg(Arg::new("ignore-tls-errors")
+			.long("ignore-tls-errors")
+			.help("Accept invalid TLS certificates (insecure)")
+			.long_help("Accept any TLS certificate, including self-signed and expired ones, for testing against mirrors. Anyone between you and the server can then change what gets downloaded, so don't use this for normal runs")
+			.takes_value(false)
+			.env("BINGIMAGE_IGNORE_TLS_ERRORS")
+			.hide(true)
+		)
+		.arg(Arg::new("read-timeout")
+			.long("read-timeout")
+			.value_name("seconds")
+			.help("Give up on a download that stops sending data for this long")
+			.long_help("Number of seconds a response can go without sending any data before giving up on it. Large downloads that are slow but still progressing aren't cut off")
+			.takes_value(true)
+			.env("BINGIMAGE_READ_TIMEOUT")
+		)
+		.arg(Arg::new("auth-header")
+			.long("auth-header")
+			.value_name("value")
+			.help("Authorization header to send with every request")
+			.long_help("Value of the Authorization header to send with every request, ex. \"Bearer <token>\", for Bing endpoints behind an authenticating gateway. It's only sent to Bing with --source bing, never to other sources or --webhook. No Authorization header is sent if this isn't set")
+			.takes_value(true)
+			.env("BINGIMAGE_AUTH")
+			.hide_env_values(true)
+		)
+		.arg(Arg::new("cache-dir")
+			.long("cache-dir")
+			.value_name("path")
+			.help("Directory to remember responses in between runs")
+			.long_help("Directory to remember the ETag and Last-Modified of responses in between runs. When set, requests are made conditional on the metadata or image having changed, and a 304 Not Modified reuses the cached metadata or the image that's already on disk")
+			.takes_value(true)
+			.env("BINGIMAGE_CACHE_DIR")
+		)
+		.arg(Arg::new("only-new-image")
+			.long("only-new-image")
+			.help("Skip markets whose image hasn't changed since the last run")
+			.long_help("Skip downloading and writing anything for a market when its image has the same hsh (an ID Bing gives each image) as the last successful run, even if the date is different. The hashes are kept in --cache-dir. Sources without image IDs are never skipped")
+			.takes_value(false)
+			.env("BINGIMAGE_ONLY_NEW_IMAGE")
+			.requires("cache-dir")
+		)
+		.arg(Arg::new("webhook")
+			.long("webhook")
+			.value_name("url")
+			.help("URL to POST a notification to after a successful run")
+			.long_help("URL to POST a JSON notification to once every image has downloaded, with the title, copyright and date of the image and the list of files. The message is in both the \"content\" and \"text\" fields, so Discord and Slack webhooks can use it as-is. A failed notification only prints a warning")
+			.takes_value(true)
+			.env("BINGIMAGE_WEBHOOK")
+		)
+		.arg(Arg::new("grayscale")
+			.long("grayscale")
+			.help("Save the images in grayscale")
+			.takes_value(false)
+			.env("BINGIMAGE_GRAYSCALE")
+		)
+		.arg(Arg::new("blur")
+			.long("blur")
+			.value_name("sigma")
+			.help("Blur the images")
+			.long_help("Blur the images with a Gaussian blur of this sigma in pixels, ex. 10 for a lock screen background. The blur is the same number of pixels at every resolution, so it looks stronger on smaller ones")
+			.takes_value(true)
+			.env("BINGIMAGE_BLUR")
+		)
+		.arg(Arg::new("brightness")
+			.long("brightness")
+			.value_name("delta")
+			.help("Brighten or darken the images")
+			.long_help("Add this to every color channel of every pixel, which goes from 0 to 255. Negative numbers darken the images, ex. -60")
+			.takes_value(true)
+			.env("BINGIMAGE_BRIGHTNESS")
+			.allow_hyphen_values(true)
+		)
+		.arg(Arg::new("title-overlay")
+			.long("title-overlay")
+			.help("Write the image's title onto it")
+			.long_help("Write the image's title onto it in a corner, on a darkened background so it can be read on any image, using the font from --overlay-font. Applied after the other filters")
+			.takes_value(false)
+			.env("BINGIMAGE_TITLE_OVERLAY")
+			.requires("overlay-font")
+		)
+		.arg(Arg::new("overlay-font")
+			.long("overlay-font")
+			.value_name("path")
+			.help("TrueType or OpenType font for --title-overlay")
+			.long_help("TrueType or OpenType font file to write the title with for --title-overlay, ex. /usr/share/fonts/truetype/dejavu/DejaVuSans.ttf")
+			.takes_value(true)
+			.env("BINGIMAGE_OVERLAY_FONT")
+		)
+		.arg(Arg::new("overlay-corner")
+			.long("overlay-corner")
+			.value_name("corner")
+			.help("Corner of the image to write the title in")
+			.long_help("Corner of the image to write the title in for --title-overlay")
+			.takes_value(true)
+			.env("BINGIMAGE_OVERLAY_CORNER")
+			.possible_values(["top-left", "top-right", "bottom-left", "bottom-right"])
+			.default_value("bottom-left")
+		)
+		.arg(Arg::new("overlay-size")
+			.long("overlay-size")
+			.value_name("pixels")
+			.help("Height of the title's text")
+			.long_help("Height of the title's text for --title-overlay, in pixels. The same at every resolution, so it's relatively bigger on smaller images")
+			.takes_value(true)
+			.env("BINGIMAGE_OVERLAY_SIZE")
+			.default_value("32")
+		)
+		.arg(Arg::new("overlay-color")
+			.long("overlay-color")
+			.value_name("#rrggbb")
+			.help("Color of the title's text")
+			.long_help("Color of the title's text for --title-overlay, as a hex code, ex. #ffcc00")
+			.takes_value(true)
+			.env("BINGIMAGE_OVERLAY_COLOR")
+			.default_value("#ffffff")
+		)
+		.arg(Arg::new("progressive")
+			.long("progressive")
+			.help("Save filtered images as progressive JPEGs")
+			.long_help("Encode the images --grayscale, --blur, --brightness and --title-overlay change as progressive JPEGs, which show a blurry version of the whole image while they load instead of loading from the top down. Useful for putting them on a website. Does nothing without a filter, since images are otherwise written exactly as Bing sends them")
+			.takes_value(false)
+			.env("BINGIMAGE_PROGRESSIVE")
+		)
+		.arg(Arg::new("chroma")
+			.long("chroma")
+			.value_name("subsampling")
+			.help("Chroma subsampling of filtered images")
+			.long_help("How much color detail to keep when encoding the images --grayscale, --blur, --brightness and --title-overlay change: 444 keeps all of it, 422 halves it horizontally and 420 in both directions, for smaller files. Makes the most difference to fine colored text and gradients. The encoder's default at the quality filtered images are saved at is 444. Does nothing without a filter, like --progressive")
+			.takes_value(true)
+			.env("BINGIMAGE_CHROMA")
+			.possible_values(["420", "422", "444"])
+		)
+		.arg(Arg::new("keep-original")
+			.long("keep-original")
+			.help("Keep the unfiltered images too")
+			.long_help("When --grayscale, --blur or --brightness is set, write the unfiltered image under its usual name as well, and the filtered one with the filters before the extension, ex. 1920x1080.jpg and 1920x1080.grayscale-blur.jpg")
+			.takes_value(false)
+			.env("BINGIMAGE_KEEP_ORIGINAL")
+		)
+		.arg(Arg::new("extract-color")
+			.long("extract-color")
+			.help("Print the average color of the image")
+			.long_help("After downloading, print the average color of the image as a hex code, ex. #1a2b3c, for theming. It's taken from the smallest resolution that was downloaded, and included in the --webhook notification")
+			.takes_value(false)
+			.env("BINGIMAGE_EXTRACT_COLOR")
+			.conflicts_with("archive")
+		)
+		.arg(Arg::new("color-sidecar")
+			.long("color-sidecar")
+			.help("Also write the average color to color.txt")
+			.long_help("Also write the --extract-color hex code to color.txt, next to the image it was taken from")
+			.takes_value(false)
+			.env("BINGIMAGE_COLOR_SIDECAR")
+			.requires("extract-color")
+		)
+		.arg(Arg::new("palette-file")
+			.long("palette-file")
+			.value_name("path")
+			.help("Write the image's main colors to a palette file")
+			.long_help("After downloading, write the image's most common colors to this file, most common first. Paths ending in .gpl get a GIMP palette, anything else gets CSS variables in :root (--color-1, --color-2, ...). Like --extract-color, they're taken from the smallest resolution that was downloaded")
+			.takes_value(true)
+			.env("BINGIMAGE_PALETTE_FILE")
+			.conflicts_with("archive")
+		)
+		.arg(Arg::new("palette-size")
+			.long("palette-size")
+			.value_name("count")
+			.help("Number of colors in the --palette-file")
+			.takes_value(true)
+			.env("BINGIMAGE_PALETTE_SIZE")
+			.default_value("5")
+			.validator(at_least_one)
+		)
+		.arg(Arg::new("metrics")
+			.long("metrics")
+			.help("Print how long downloads took")
+			.long_help("At the end, print how long each download took and how fast it was, and the total bytes, time and throughput of the whole run. Useful for tuning --jobs and the timeouts")
+			.takes_value(false)
+			.env("BINGIMAGE_METRICS")
+		)
+		.arg(Arg::new("summary-json-to")
+			.long("summary-json-to")
+			.value_name("path")
+			.help("Write a JSON report of the run")
+			.long_help("After downloading, write a JSON report of the run to this file for monitoring: the files that were written with their sizes and times, the ones that weren't and why, the total size and time, the time the run started and bingimage's version. Downloads cancelled by --exit-on-first-success aren't in either list")
+			.takes_value(true)
+			.env("BINGIMAGE_SUMMARY_JSON_TO")
+		)
+		.arg(Arg::new("compare-resolutions")
+			.long("compare-resolutions")
+			.help("Print a comparison of the downloaded file sizes")
+			.long_help("After downloading, print a table comparing the file size of each resolution, to help pick which ones are worth keeping")
+			.takes_value(false)
+			.env("BINGIMAGE_COMPARE_RESOLUTIONS")
+		)
+		.arg(Arg::new("ephemeral")
+			.long("ephemeral")
+			.help("Delete the downloaded images after comparing them")
+			.long_help("Delete the downloaded images after printing the --compare-resolutions table")
+			.takes_value(false)
+			.env("BINGIMAGE_EPHEMERAL")
+			.requires("compare-resolutions")
+			.conflicts_with("archive")
+		)
+		.arg(Arg::new("check")
+			.long("check")
+			.help("Only check that Bing is serving an image")
+			.long_help("Only fetch the metadata and check that it describes an image, without downloading anything. Prints nothing, and exits with 0 if the metadata is valid or 1 if it isn't or couldn't be fetched")
+			.takes_value(false)
+			.env("BINGIMAGE_CHECK")
+		)
+		.arg(Arg::new("redownload-corrupt")
+			.long("redownload-corrupt")
+			.help("Download corrupt images in the -p directories again")
+			.long_help("Instead of downloading today's image, look for images in the -p directories that can't be decoded, like ones cut off by an interrupted run, and replace them with a fresh download. Goes by --output-template and --date-format to tell which image and resolution each file is, or when it was modified if its name doesn't have a date, and can only repair images from the last 8 days, which is as far back as Bing's API goes")
+			.takes_value(false)
+			.env("BINGIMAGE_REDOWNLOAD_CORRUPT")
+			.conflicts_with_all(&["archive", "check", "watch", "only-metadata"])
+		)
+		.arg(Arg::new("probe-supported-resolutions")
+			.long("probe-supported-resolutions")
+			.help("Check which resolutions Bing has today's image in")
+			.long_help("Instead of downloading anything, send a HEAD request for today's image in every resolution bingimage lists as supported and a few other common ones, and print which ones Bing has and which it doesn't. Exits with 1 if the metadata couldn't be fetched. For checking whether the list of supported resolutions used by --auto-correct is still right")
+			.takes_value(false)
+			.env("BINGIMAGE_PROBE_SUPPORTED_RESOLUTIONS")
+			.conflicts_with_all(&["check", "watch", "redownload-corrupt"])
+		)
+		.arg(Arg::new("fail-if-empty-archive")
+			.long("fail-if-empty-archive")
+			.help("Exit with 3 if Bing answers with no images")
+			.long_help("Exit with 3 instead of 1 when Bing answers with an empty list of images, so monitoring can tell an empty feed apart from Bing being unreachable (which is still 1). Works with --check too")
+			.takes_value(false)
+			.env("BINGIMAGE_FAIL_IF_EMPTY_ARCHIVE")
+			.conflicts_with("watch")
+		)
+		.arg(Arg::new("watch")
+			.long("watch")
+			.help("Keep running, and download again on a schedule")
+			.long_help("Keep running instead of exiting, and download everything again every --interval, or every day at --at. The first run starts right away. Ctrl-C stops it")
+			.takes_value(false)
+			.env("BINGIMAGE_WATCH")
+			.conflicts_with_all(&["check", "repeat"])
+		)
+		.arg(Arg::new("interval")
+			.long("interval")
+			.value_name("seconds")
+			.help("Time between --watch runs")
+			.long_help("Time between the start of one --watch run and the next, in seconds. If a run takes longer than this, the next one starts as soon as it's done")
+			.takes_value(true)
+			.env("BINGIMAGE_INTERVAL")
+			.default_value("86400")
+			.validator(at_least_one)
+		)
+		.arg(Arg::new("at")
+			.long("at")
+			.value_name("HH:MM")
+			.help("Time of day to run --watch at")
+			.long_help("Run --watch every day at this local time, ex. 04:00, instead of every --interval")
+			.takes_value(true)
+			.env("BINGIMAGE_AT")
+			.requires("watch")
+			.validator(|value| NaiveTime::parse_from_str(value, "%H:%M"))
+		)
+		.arg(Arg::new("lockfile")
+			.long("lockfile")
+			.value_name("file")
+			.help("Exit if another run holding this lock file is in progress")
+			.long_help("Take an exclusive lock on this file (created if it doesn't exist) for the whole run, and exit with 1 if another run already has it. Stops ex. a cron job and a manual run from writing to the same directory at once. The lock is released when bingimage exits, however it exits")
+			.takes_value(true)
+			.env("BINGIMAGE_LOCKFILE")
+		)
+		.arg(Arg::new("validate-schema")
+			.long("validate-schema")
+			.help("Check the metadata against the shape bingimage expects")
+			.long_help("Check the metadata against a JSON Schema of the shape bingimage expects, with url, urlbase, title, copyright and startdate all required, and exit with 1 listing every field that's missing or the wrong type. Stricter than the checks done normally, for noticing when Bing changes its format")
+			.takes_value(false)
+			.env("BINGIMAGE_VALIDATE_SCHEMA")
+		)
+		.arg(Arg::new("strict-json")
+			.long("strict-json")
+			.help("Reject metadata responses with anything after the JSON")
+			.long_help("Treat a metadata response as an error if anything comes after the JSON in it, even whitespace, and say at which byte it starts. Normally trailing whitespace is allowed. Meant for networks where something might be injecting content into responses")
+			.takes_value(false)
+			.env("BINGIMAGE_STRICT_JSON")
+		)
+		.arg(Arg::new("verify-checksum-against")
+			.long("verify-checksum-against")
+			.value_name("file")
+			.help("Only check files against the SHA-256 hashes in a .sha256 file")
+			.long_help("Only check that files match the SHA-256 hashes listed in a .sha256 file, without downloading anything. The file is in the format sha256sum writes, with paths relative to it, or just a hash for the file it's named after, ex. 1920x1080.jpg.sha256. Exits with 1 if any file doesn't match or couldn't be read")
+			.takes_value(true)
+			.env("BINGIMAGE_VERIFY_CHECKSUM_AGAINST")
+		)
+		.arg(Arg::new("print-config")
+			.long("print-config")
+			.help("Print the settings that would be used as JSON, then exit")
+			.long_help("Print the value of every option as JSON, whether it came from the command line, an environment variable or a default, then exit without downloading anything. Values of options that can be secret, like --auth-header, are hidden")
+			.takes_value(false)
+			.env("BINGIMAGE_PRINT_CONFIG")
+		)
+		.arg(Arg::new("version-info")
+			.long("version-info")
+			.help("Print version and build information for bug reports, then exit")
+			.long_help("Print bingimage's version, the git commit it was built from, the version of Rust it was built with and the platform it was built for, then exit. Worth including in bug reports")
+			.takes_value(false)
+			.env("BINGIMAGE_VERSION_INFO")
+		)
+		.arg(Arg::new("trace")
+			.long("trace")
+			.help("Log every HTTP request and response")
+			.long_help("Log the URL of every HTTP request, and the status, content type and length of its response, to stderr")
+			.takes_value(false)
+			.env("BINGIMAGE_TRACE")
+		)
+		.arg(Arg::new("url-strategy")
+			.long("url-strategy")
+			.value_name("strategy")
+			.help("How to build the URL of each resolution of the image")
+			.long_help("How to build the URL of each resolution of the image: add the resolution to the end of the API's urlbase field (urlbase), or replace --replace-token in its url field with it (replace), which is how older versions of bingimage did it. urlbase falls back to replace for responses without a urlbase, like the other sources'. Only useful if one of them stops working")
+			.takes_value(true)
+			.env("BINGIMAGE_URL_STRATEGY")
+			.possible_values(["urlbase", "replace"])
+			.default_value("urlbase")
+			.hide(true)
+		)
+		.arg(Arg::new("url-template")
+			.long("url-template")
+			.value_name("template")
+			.help("How to build image URLs from the API's urlbase")
+			.long_help("Template for the URL of each resolution of the image with --url-strategy urlbase. {base} is replaced with the API's urlbase, {w} and {h} with the width and height, and {res} with both, ex. 1920x1080. URLs that start with / are on bing.com")
+			.takes_value(true)
+			.env("BINGIMAGE_URL_TEMPLATE")
+			.default_value("{base}_{res}.jpg")
+			.hide(true)
+		)
+		.arg(Arg::new("replace-token")
+			.long("replace-token")
+			.value_name("token")
+			.help("Part of the API's image URL to replace with the resolution (experimental)")
+			.long_help("Part of the API's image URL to replace with the requested resolution (experimental). Only used with --url-strategy replace, and only useful if Bing changes its URL format before bingimage is updated")
+			.takes_value(true)
+			.env("BINGIMAGE_REPLACE_TOKEN")
+			.default_value("1920x1080")
+			.hide(true)
+		)
+		.arg(Arg::new("repeat")
+			.long("repeat")
+			.value_name("count")
+			.help("Run the downloads this many times (for benchmarking)")
+			.long_help("Testing tool, not for normal use: download everything this many times, reusing the metadata from the first fetch, and print how long each iteration and the whole run took")
+			.takes_value(true)
+			.env("BINGIMAGE_REPEAT")
+			.default_value("1")
+			.validator(at_least_one)
+			.conflicts_with("archive")
+			.hide(true)
+		)
+		.arg(Arg::new("max-dimension")
+			.long("max-dimension")
+			.help("Cap resolutions at the largest size Bing serves")
+			.long_help("Cap resolutions at the largest size Bing serves, its UHD size of 3840x2160 (2160x3840 for portrait resolutions), with a warning. Bigger resolutions always fail")
+			.takes_value(false)
+			.env("BINGIMAGE_MAX_DIMENSION")
+		)
+		.arg(Arg::new("auto-correct")
+			.long("auto-correct")
+			.help("Download the nearest supported resolution instead of an unsupported one")
+			.long_help("Replace any resolution Bing doesn't serve with the nearest one it does, instead of only printing a warning")
+			.takes_value(false)
+			.env("BINGIMAGE_AUTO_CORRECT")
+		)
+		.arg(Arg::new("prefer-portrait-for-phones")
+			.long("prefer-portrait-for-phones")
+			.help("Match phone-sized resolutions with portrait images")
+			.long_help("When finding the nearest supported resolution, only consider portrait ones for phone-like resolutions, at least 1.5 times as tall as they are wide (ex. 9:16 or 9:19.5), and only landscape ones for resolutions at least as wide as they are tall. Anything in between, like a tablet held upright, is matched with either")
+			.takes_value(false)
+			.env("BINGIMAGE_PREFER_PORTRAIT_FOR_PHONES")
 		);
-	let res_error = app.error(clap::ErrorKind::InvalidValue, "Can't parse resolution value. See help for information on how to format it.");
-	let path_error = app.error(clap::ErrorKind::InvalidValue, "Output path must be a directory.");
-	let app = app.get_matches();
+	let matches = app.get_matches_mut();
+	if matches.is_present("version-info") {
+		println!("bingimage {}", env!("CARGO_PKG_VERSION"));
+		println!("commit: {}", env!("BINGIMAGE_GIT_COMMIT"));
+		println!("rustc: {}", env!("BINGIMAGE_RUSTC_VERSION"));
+		println!("target: {}", env!("BINGIMAGE_TARGET"));
+		return Ok(())
+	}
+	let config = Config::from_matches(&mut app, &matches);
+	if config.json_out.as_deref() == Some("-") {
+		STATUS_TO_STDERR.store(true, Ordering::Relaxed);
+	}
+
+	if matches.is_present("print-config") {
+		// Serializing the config can't fail
+		println!("{}", serde_json::to_string_pretty(&config).unwrap());
+		return Ok(())
+	}
+
+	if matches.subcommand_matches("list-markets").is_some() {
+		for (code, region) in MARKETS {
+			println!("{:<7}{}", code, region);
+		}
+		return Ok(())
+	}
+
+	if let Some(completion) = matches.subcommand_matches("completion") {
+		let shell: Shell = completion.value_of_t_or_exit("shell");
+		clap_complete::generate(shell, &mut app, "bingimage", &mut std::io::stdout());
+		return Ok(())
+	}
+
+	if matches.subcommand_matches("list-downloaded").is_some() {
+		if config.paths.is_empty() {
+			app.error(ErrorKind::MissingRequiredArgument, "list-downloaded needs the directories to list with -p.").exit()
+		}
+		for path in &config.paths {
+			if config.paths.len() > 1 {
+				println!("{:?}:", path);
+			}
+			if let Err(error) = list_downloaded(path, &config) {
+				eprintln!("Error reading directory {:?}: {}", path, error);
+				std::process::exit(1)
+			}
+		}
+		return Ok(())
+	}
+
+	if let Some(manifest) = &config.verify_checksum_against {
+		match verify_checksums(manifest) {
+			Ok(true) => return Ok(()),
+			Ok(false) => std::process::exit(1),
+			Err(error) => {
+				eprintln!("Error reading file {:?}: {}", manifest, error);
+				std::process::exit(1)
+			}
+		}
+	}
+
+	// Held until bingimage exits, which is when the OS releases the lock
+	let _lock = match &config.lockfile {
+		Some(lock_path) => {
+			let lock = File::create(lock_path)?;
+			match lock.try_lock_exclusive() {
+				Ok(_) => Some(lock),
+				Err(error) if error.kind() == fs2::lock_contended_error().kind() => {
+					eprintln!("Another bingimage run is in progress (holding {:?})", lock_path);
+					std::process::exit(1)
+				},
+				Err(error) => {
+					eprintln!("Error locking file {:?}: {}", lock_path, error);
+					std::process::exit(1)
+				}
+			}
+		},
+		None => None
+	};
+
+	if let Some(temp_dir) = &config.temp_dir {
+		for path in config.paths.iter().filter(|path| !is_same_filesystem(temp_dir, path)) {
+			eprintln!("Warning: --temp-dir {:?} is on a different filesystem than {:?}, so files are copied there instead of being moved, and can be seen partially written", temp_dir, path);
+		}
+	}
+
+	// The Authorization header is only for Bing's gateway, so it isn't sent to other sources or webhooks
+	let mut headers = HeaderMap::new();
+	if let Some(auth) = &config.auth_header {
+		if config.source == "bing" {
+			headers.insert(header::AUTHORIZATION, auth.clone());
+		} else {
+			eprintln!("Warning: --auth-header is only sent to Bing, so it isn't used with --source {}", config.source);
+		}
+	}
+
+	let cache = match &config.cache_dir {
+		Some(dir) => Some(Arc::new(Cache::load(dir)?)),
+		None => None
+	};
+
+	if config.ignore_tls_errors {
+		eprintln!("WARNING: --ignore-tls-errors is set, so TLS certificates aren't being checked. Downloads can be tampered with by anyone on the network");
+	}
+	let build_client = |headers: HeaderMap| {
+		let mut client = reqwest::Client::builder()
+			.default_headers(headers)
+			.danger_accept_invalid_certs(config.ignore_tls_errors);
+		if let Some(seconds) = config.connect_timeout {
+			client = client.connect_timeout(Duration::from_secs(seconds));
+		}
+		client.build()
+	};
+
+	let options = RequestOptions {
+		client: build_client(headers)?,
+		plain_client: build_client(HeaderMap::new())?,
+		cache: cache.clone(),
+		grace: Duration::from_secs(config.startup_grace),
+		rate_limit: config.ratelimit_global.map(|rate| Arc::new(RateLimiter::new(rate))),
+		retries: config.retries,
+		retry_budget: config.retry_budget.map(|budget| Arc::new(AtomicU32::new(budget))),
+		read_timeout: config.read_timeout.map(Duration::from_secs),
+		head_check: config.head_check,
+		save_response: config.save_response.clone(),
+		pretty_response: config.pretty_response,
+		strict_json: config.strict_json,
+		retry_on_partial: config.retry_on_partial,
+		trace: config.trace
+	};
+
+	let markets: Vec<Option<String>> = match config.markets.is_empty() {
+		true => vec![None],
+		false => config.markets.iter().cloned().map(Some).collect()
+	};
+	let source = source_named(&config.source);
+	if let Some(info) = matches.subcommand_matches("info") {
+		for market in &markets {
+			if markets.len() > 1 {
+				println!("{}:", market.as_deref().unwrap_or_default());
+			}
+			match source.fetch(market.as_deref(), &options).await {
+				Ok(json) => print_info(&json["images"][0], info.is_present("raw")),
+				Err(error) => {
+					eprintln!("Error fetching metadata: {}", error);
+					std::process::exit(1)
+				}
+			}
+		}
+		return Ok(())
+	}
+	if config.check {
+		for market in &markets {
+			match source.fetch(market.as_deref(), &options).await {
+				Ok(json) if config.fail_if_empty_archive && is_empty_feed(&json) => std::process::exit(EMPTY_FEED_EXIT_CODE),
+				Ok(json) if is_valid_metadata(&json) && (!config.validate_schema || matches_schema(&json)) => (),
+				_ => std::process::exit(1)
+			}
+		}
+		return Ok(())
+	}
+	let config = Arc::new(config);
+	if config.redownload_corrupt {
+		if !redownload_corrupt(&config, &markets, &options).await? {
+			std::process::exit(1)
+		}
+		return Ok(())
+	}
+	if config.probe_supported_resolutions {
+		if !probe_resolutions(&config, &markets, &options).await {
+			std::process::exit(1)
+		}
+		return Ok(())
+	}
+
+	// Clap's requirements should make this impossible, but running without any output is never intended
+	if config.resolutions.is_empty() && !config.readme && !config.only_metadata && config.json_out.is_none() {
+		app.error(ErrorKind::MissingRequiredArgument, "Nothing to do: pass -r to download images, or -m, --only-metadata or --json-out for the metadata.").exit()
+	}
+	if !config.watch {
+		let succeeded = run(config.clone(), &markets, &source, &options).await?;
+		if let Some(cache) = cache {
+			cache.save()?;
+		}
+		if !succeeded {
+			std::process::exit(1)
+		}
+		return Ok(())
+	}
+
+	// Ctrl-C is checked for everywhere the watch can wait, whether it's during a run or between them
+	let ctrl_c = tokio::signal::ctrl_c();
+	tokio::pin!(ctrl_c);
+	loop {
+		status!("Starting run at {}", Local::now().format("%Y-%m-%d %H:%M:%S"));
+		let run_started = Instant::now();
+		tokio::select! {
+			result = run(config.clone(), &markets, &source, &options) => match result {
+				Ok(true) => status!("Run finished"),
+				Ok(false) => eprintln!("Run failed"),
+				Err(error) => eprintln!("Run failed: {}", error)
+			},
+			_ = &mut ctrl_c => break
+		}
+		if let Some(cache) = &cache {
+			if let Err(error) = cache.save() {
+				eprintln!("Error saving cache: {}", error);
+			}
+		}
+
+		let wait = match config.at {
+			Some(time) => until_next(time),
+			// The interval is from the start of this run, so runs don't drift later by how long they take
+			None => Duration::from_secs(config.interval).saturating_sub(run_started.elapsed())
+		};
+		status!("Next run at {}", (Local::now() + chrono::Duration::from_std(wait).unwrap_or_else(|_| chrono::Duration::zero())).format("%Y-%m-%d %H:%M:%S"));
+		tokio::select! {
+			_ = tokio::time::sleep(wait) => (),
+			_ = &mut ctrl_c => break
+		}
+	}
+	status!("Stopping");
+	Ok(())
+}
+
+/// Start writing whichever of README.md and --json-out are on for `image`, with its market's
+/// `prefix`
+fn spawn_metadata(image: &ImageProperties, prefix: &str, output: &Arc<Output>, config: &Arc<Config>) -> Vec<tokio::task::JoinHandle<()>> {
+	let mut handles = Vec::new();
+	if config.readme || config.only_metadata {
+		let file_name = format!("{}README.md", prefix);
+		handles.push(tokio::spawn(create_metadata(image.clone(), output.clone(), file_name, config.clone())))
+	}
+	if let Some(json_out) = &config.json_out {
+		let properties = match config.strip_query {
+			true => ImageProperties { url: Arc::new(strip_query(&image.url)), ..image.clone() },
+			false => image.clone()
+		};
+		handles.push(tokio::spawn(create_json(properties, json_out.clone(), config.clone())))
+	}
+	handles
+}
+
+/// How long it is until the next time the local time is `time`, which is tomorrow if it's already
+/// past that today
+fn until_next(time: NaiveTime) -> Duration {
+	let now = Local::now().naive_local();
+	let mut next = now.date().and_time(time);
+	if next <= now {
+		next += chrono::Duration::days(1);
+	}
+	(next - now).to_std().unwrap_or_default()
+}
+
+/// Fetch the metadata and download everything `config` asks for, once. Returns whether the
+/// metadata could be fetched, which is the only failure that stops a run unless --fail-fast is
+/// set: errors downloading and writing individual files are printed to stderr without stopping
+/// the rest.
+async fn run(config: Arc<Config>, markets: &[Option<String>], source: &Arc<dyn Source>, options: &RequestOptions) -> Result<bool, Box<dyn std::error::Error>> {
+	let mut resolutions: Vec<Arc<Resolution>> = config.resolutions.iter().copied().map(Arc::new).collect();
+	// Permits for --jobs are handed out in the order downloads ask for them, which is this order
+	match config.download_order.as_str() {
+		"smallest" => resolutions.sort_by_key(|resolution| resolution.pixels()),
+		"largest" => resolutions.sort_by_key(|resolution| std::cmp::Reverse(resolution.pixels())),
+		_ => ()
+	}
+
+	let run_started = Instant::now();
+	let run_started_at = Local::now();
+	// Fetch the metadata for every market up front, a limited number at a time
+	let market_limit = Arc::new(Semaphore::new(config.parallel_markets_limit));
+	let mut fetches = Vec::new();
+	for market in markets {
+		let market = market.clone();
+		let market_limit = market_limit.clone();
+		let options = options.clone();
+		let source = source.clone();
+		fetches.push(tokio::spawn(async move {
+			let _permit = market_limit.acquire_owned().await;
+			source.fetch(market.as_deref(), &options).await
+		}));
+	}
+	let mut responses = Vec::new();
+	for fetch in fetches {
+		match fetch.await? {
+			Ok(json) if config.fail_if_empty_archive && is_empty_feed(&json) => {
+				eprintln!("Bing answered with no images");
+				std::process::exit(EMPTY_FEED_EXIT_CODE)
+			},
+			Ok(json) if config.validate_schema && !matches_schema(&json) => return Ok(false),
+			// Caught here, since a broken URL only fails confusingly once it's requested
+			Ok(json) if !resolutions.is_empty() && !is_valid_image_url(json["images"][0]["url"].as_str().unwrap_or_default()) => {
+				eprintln!("Error in metadata: the image URL {} isn't a path on bing.com or an http(s) URL", json["images"][0]["url"]);
+				return Ok(false)
+			},
+			Ok(json) => responses.push(json),
+			Err(error) => {
+				eprintln!("Error fetching metadata: {}", error);
+				return Ok(false)
+			}
+		}
+	}
+	if config.title_fallback {
+		fill_missing_text(&mut responses, markets, &**source, options).await;
+	}
+
+	// Only create the archive once there's something to put in it
+	let output = Arc::new(match &config.archive {
+		Some(archive_path) => Output::Archive(Archive::create(archive_path.clone())?),
+		None => Output::Directories(config.paths.clone())
+	});
+
+	// Which markets' images are the same as last time, for --only-new-image
+	let unchanged: Vec<bool> = markets.iter().zip(&responses).map(|(market, json)| {
+		match (config.only_new_image, &options.cache, json["images"][0]["hsh"].as_str()) {
+			(true, Some(cache), Some(hash)) => cache.last_seen(market.as_deref().unwrap_or_default()).as_deref() == Some(hash),
+			_ => false
+		}
+	}).collect();
+
+	let jobs = Arc::new(Semaphore::new(config.jobs));
+	let started = Instant::now();
+	let mut images;
+	let mut attempted;
+	let mut downloaded;
+	let mut failed;
+	let mut iteration = 1;
+	// Whether --fail-fast stopped the run, which skips everything after the downloads
+	let mut stopped = false;
+	loop {
+		let iteration_started = Instant::now();
+
+		let mut downloads = Vec::new();
+		let mut handles = Vec::new();
+		// Metadata to write once the downloads are done, with the prefix of its market's files and how many of them there are
+		let mut deferred = Vec::new();
+		// Whether each download succeeded, as they finish, for --exit-on-first-success
+		let (finished, mut finished_receiver) = tokio::sync::mpsc::unbounded_channel();
+		// Held by downloads while they write, so stopping early waits for writes instead of cutting them off
+		let writing = Arc::new(tokio::sync::RwLock::new(()));
+		// What each market's image is, for the webhook
+		images = Vec::new();
+
+		for ((market, json), &unchanged) in markets.iter().zip(&responses).zip(&unchanged) {
+			if unchanged {
+				status!("Image unchanged since last run for {}, skipping it", market.as_deref().unwrap_or("the default market"));
+				continue
+			}
+			let meta = &json["images"][0];
+
+			let url = Arc::new(meta["url"].as_str().unwrap_or_default().to_string());
+			let urlbase = image_urlbase(meta).map(|urlbase| Arc::new(urlbase.to_string()));
+			if urlbase.is_none() && config.url_strategy == "urlbase" && meta["urlbase"].as_str().is_some_and(|urlbase| !urlbase.is_empty()) {
+				eprintln!("Warning: the image's urlbase {} isn't a path on bing.com or an http(s) URL, using its url instead", meta["urlbase"]);
+			}
+			let (title, copyright) = image_text(meta);
+			let image_date = match meta["startdate"].as_str().and_then(|date| NaiveDate::parse_from_str(date, "%Y%m%d").ok()) {
+				Some(date) => date,
+				None => {
+					eprintln!("Can't parse the image's date, using today's date instead");
+					Local::now().date_naive()
+				}
+			};
+			let date = Arc::new(image_date.format(&config.date_format).to_string());
+
+			// Fields that aren't always there, and only end up in the JSON metadata
+			let mut extra = serde_json::Map::new();
+			for field in EXTRA_FIELDS {
+				if let Some(value) = meta.get(field).filter(|value| !value.is_null()) {
+					extra.insert(field.to_string(), value.clone());
+				}
+			}
+			let extra = Arc::new(extra);
+
+			images.push(serde_json::json!({
+				"market": market,
+				"title": *title,
+				"copyright": copyright.as_deref(),
+				"date": *date
+			}));
+
+			// With more than one market, each one gets its own subdirectory
+			let prefix = match market {
+				Some(market) if markets.len() > 1 => {
+					if let Output::Directories(paths) = &*output {
+						for path in paths {
+							std::fs::create_dir_all(path.join(market))?;
+						}
+					}
+					format!("{}/", market)
+				},
+				_ => String::new()
+			};
+
+			let downloads_before = downloads.len();
+			// The resolution is only a placeholder, since this is shared by every resolution and the metadata
+			let image = ImageProperties {
+				resolution: Arc::new(Resolution::new(0, 0)),
+				url,
+				urlbase,
+				title,
+				copyright,
+				date,
+				extra
+			};
+
+			let url_hash = image.url_hash();
+			for (resolution, image_url) in resolutions.iter().zip(image.urls(&resolutions, &config)) {
+				let properties = ImageProperties { resolution: resolution.clone(), ..image.clone() };
+				let mut file_name = format!("{}{}", prefix, render_template(&config.output_template, resolution, image_date, &image.date, &url_hash));
+				if config.normalize_extension {
+					file_name = normalize_extension(&file_name);
+				}
+				if !is_valid_file_name(&file_name) {
+					eprintln!("File name {:?} from --output-template isn't a relative path inside the output directory, skipping it", file_name);
+					continue
+				}
+				if let Output::Directories(paths) = &*output {
+					let directories = paths.iter().map(|path| path.join(&file_name).parent().unwrap().to_path_buf());
+					let missing: Vec<PathBuf> = directories.filter(|directory| !directory.is_dir()).collect();
+					if !missing.is_empty() {
+						if config.create_dirs {
+							for directory in &missing {
+								std::fs::create_dir_all(directory)?;
+							}
+						} else {
+							eprintln!("Directory {:?} doesn't exist, skipping {}. Use --create-dirs to create it", missing[0], file_name);
+							continue
+						}
+					}
+				}
+				if config.copyright_sidecar {
+					let sidecar_name = Path::new(&file_name).with_extension("txt").to_string_lossy().into_owned();
+					handles.push(tokio::spawn(create_sidecar(properties.clone(), output.clone(), sidecar_name, config.clone())));
+				}
+				let output = output.clone();
+				let jobs = jobs.clone();
+				let config = config.clone();
+				let options = options.clone();
+				let finished = finished.clone();
+				let writing = writing.clone();
+				downloads.push(tokio::spawn(async move {
+					let _permit = jobs.acquire_owned().await;
+					let result = download(properties, image_url, output, file_name, config, options, &writing).await;
+					// Nothing is listening without --exit-on-first-success
+					let _ = finished.send(result.is_ok());
+					result
+				}))
+			}
+			match config.skip_metadata_on_download_failure {
+				true => deferred.push((image, prefix, downloads.len() - downloads_before)),
+				false => handles.extend(spawn_metadata(&image, &prefix, &output, &config))
+			}
+		}
+
+		attempted = downloads.len();
+		downloaded = Vec::new();
+		failed = Vec::new();
+		if config.exit_on_first_success || config.fail_fast {
+			// Stops at the first success for --exit-on-first-success or the first failure for --fail-fast, or
+			// once every download has finished and dropped its sender
+			drop(finished);
+			while let Some(succeeded) = finished_receiver.recv().await {
+				if succeeded == config.exit_on_first_success {
+					break
+				}
+			}
+			// Downloads that are writing finish first, so what they wrote is counted as downloaded. The
+			// rest are stuck waiting for the lock until they're aborted, so they never start writing.
+			let _stopped_writing = writing.write().await;
+			for download in &downloads {
+				download.abort();
+			}
+		}
+		for download in downloads {
+			match download.await {
+				Ok(Ok(file)) => downloaded.push(file),
+				Ok(Err(failure)) => failed.push(failure),
+				Err(error) if error.is_cancelled() => (),
+				Err(error) => return Err(error.into())
+			}
+		}
+		if config.fail_fast && !failed.is_empty() {
+			eprintln!("Stopping after the first failed download");
+			stopped = true;
+		}
+		if config.exit_on_first_success && !downloaded.is_empty() {
+			attempted = downloaded.len();
+		}
+		for (image, prefix, attempted) in deferred.into_iter().filter(|_| !stopped) {
+			match attempted > 0 && !downloaded.iter().any(|file| file.file_name.starts_with(&prefix)) {
+				true => eprintln!("No images of \"{}\" downloaded, skipping its metadata", image.title),
+				false => handles.extend(spawn_metadata(&image, &prefix, &output, &config))
+			}
+		}
+		// Metadata that's already being written is finished even when stopping, so it isn't left half done
+		for handle in handles {
+			tokio::try_join!(handle)?;
+		}
+		if stopped {
+			break
+		}
+
+		if config.repeat > 1 {
+			status!("Iteration {} took {:.3}s", iteration, iteration_started.elapsed().as_secs_f64());
+		}
+		if iteration >= config.repeat {
+			break
+		}
+		iteration += 1;
+	}
+	if config.repeat > 1 {
+		let total = started.elapsed().as_secs_f64();
+		status!("{} iterations took {:.3}s, {:.3}s on average", config.repeat, total, total / f64::from(config.repeat));
+	}
+	if stopped {
+		if let Output::Archive(archive) = &*output {
+			archive.finish()?;
+		}
+		return Ok(false)
+	}
+	// Only remembered once everything worked, so a failed download is tried again next time
+	if let (true, Some(cache), true) = (config.only_new_image, &options.cache, failed.is_empty()) {
+		for (market, json) in markets.iter().zip(&responses) {
+			if let Some(hash) = json["images"][0]["hsh"].as_str() {
+				cache.set_last_seen(market.as_deref().unwrap_or_default(), hash);
+			}
+		}
+	}
+
+	if let (true, Output::Directories(paths)) = (config.hardlink_duplicates, &*output) {
+		link_duplicates(paths, &downloaded);
+	}
+
+	// The smallest image is the cheapest to decode, and has the same colors as the rest
+	let color_source = output.directory()
+		.zip(downloaded.iter().min_by_key(|file| file.resolution.pixels()))
+		.map(|(path, file)| path.join(&file.file_name));
+	let mut color = None;
+	if let (true, Some(file_path)) = (config.extract_color, &color_source) {
+		let read_path = file_path.clone();
+		match run_image_job(&config, move || std::fs::read(&read_path).map_err(Into::into).and_then(|bytes| average_color(&bytes))).await {
+			Ok(hex) => {
+				status!("Average color of {:?}: {}", file_path, hex);
+				if config.color_sidecar {
+					let sidecar_path = file_path.with_file_name("color.txt");
+					match std::fs::write(&sidecar_path, format!("{}\n", hex)) {
+						Ok(_) => status!("Successfully written file {:?}", sidecar_path),
+						Err(error) => eprintln!("Error writing file {:?}: {}", sidecar_path, error)
+					}
+				}
+				color = Some(hex);
+			},
+			Err(error) => eprintln!("Error reading colors from {:?}: {}", file_path, error)
+		}
+	}
+	if let (Some(palette_path), Some(file_path)) = (&config.palette_file, &color_source) {
+		let (read_path, size) = (file_path.clone(), config.palette_size);
+		match run_image_job(&config, move || std::fs::read(&read_path).map_err(Into::into).and_then(|bytes| palette(&bytes, size))).await {
+			Ok(colors) => match std::fs::write(palette_path, render_palette(&colors, palette_path)) {
+				Ok(_) => status!("Successfully written file {:?}", palette_path),
+				Err(error) => eprintln!("Error writing file {:?}: {}", palette_path, error)
+			},
+			Err(error) => eprintln!("Error reading colors from {:?}: {}", file_path, error)
+		}
+	}
+
+	if let (Some(webhook), true) = (&config.webhook, downloaded.len() == attempted) {
+		notify(webhook, images, &downloaded, &output, color.as_deref(), options).await;
+	}
+
+	if config.metrics {
+		print_metrics(&downloaded, run_started.elapsed());
+	}
+	if let Some(summary_path) = &config.summary_json_to {
+		let summary = run_summary(&downloaded, &failed, run_started_at, run_started.elapsed());
+		// Serializing a JsonValue can't fail
+		match std::fs::write(summary_path, serde_json::to_string_pretty(&summary).unwrap() + "\n") {
+			Ok(_) => status!("Successfully written file {:?}", summary_path),
+			Err(error) => eprintln!("Error writing file {:?}: {}", summary_path, error)
+		}
+	}
+	if config.compare_resolutions {
+		print_comparison(&downloaded);
+		if let (true, Output::Directories(paths)) = (config.ephemeral, &*output) {
+			for path in paths {
+				for file in &downloaded {
+					let file_path = path.join(&file.file_name);
+					if let Err(error) = std::fs::remove_file(&file_path) {
+						eprintln!("Error removing file {:?}: {}", file_path, error);
+					}
+				}
+			}
+		}
+	}
+	if let Output::Archive(archive) = &*output {
+		archive.finish()?;
+	}
+	Ok(true)
+}
+
+/// Fields of an image's metadata that `info` prints, see [print_info]
+const INFO_FIELDS: [&str; 4] = ["title", "copyright", "startdate", "url"];
+
+/// Fields of an image's metadata that `info --raw` prints first, see [print_info]
+const RAW_INFO_FIELDS: [&str; 10] = ["url", "urlbase", "title", "copyright", "copyrightlink", "startdate", "enddate", "fullstartdate", "hsh", "quiz"];
+
+/// Print the fields of `meta`, an image from the API's response, as `field: value` lines.
+/// 
+/// Normally that's just [INFO_FIELDS]. With `raw`, it's every one of [RAW_INFO_FIELDS], then the
+/// rest of the fields the API sent. Strings are printed as they are, and anything else as JSON.
+fn print_info(meta: &JsonValue, raw: bool) {
+	let fields: Vec<&str> = match raw {
+		true => RAW_INFO_FIELDS.into_iter()
+			.chain(meta.as_object().into_iter().flat_map(|fields| fields.keys().map(String::as_str)).filter(|field| !RAW_INFO_FIELDS.contains(field)))
+			.collect(),
+		false => INFO_FIELDS.to_vec()
+	};
+	let width = fields.iter().map(|field| field.len()).max().unwrap_or_default() + 2;
+	for field in fields {
+		let value = match &meta[field] {
+			JsonValue::Null => "(missing)".to_string(),
+			JsonValue::String(text) => text.clone(),
+			value => value.to_string()
+		};
+		println!("{:<width$}{}", format!("{}:", field), value, width = width);
+	}
+}
+
+/// Title used when the API doesn't give one
+const UNTITLED: &str = "Untitled";
+
+/// Title and copyright of `meta`, an image from the API's response. Missing, null and empty fields
+/// are all treated as not being there, so the title falls back to [UNTITLED].
+fn image_text(meta: &JsonValue) -> (Arc<String>, Option<Arc<String>>) {
+	let text = |field: &str| meta[field].as_str().filter(|value| !value.is_empty()).map(|value| Arc::new(value.to_string()));
+	let title = text("title").unwrap_or_else(|| Arc::new(UNTITLED.to_string()));
+	(title, text("copyright"))
+}
+
+/// Optional fields from the API that are passed through to the JSON metadata as-is.
+/// 
+/// `hsh` identifies the image, `quiz` links to the homepage quiz, and `bot`/`drk` are hints for
+/// placing text over the image.
+const EXTRA_FIELDS: [&str; 4] = ["hsh", "quiz", "bot", "drk"];
+
+/// Placeholders that can be used in --output-template
+const TEMPLATE_PLACEHOLDERS: [&str; 6] = ["resolution", "date", "year", "month", "day", "urlhash"];
+
+/// Whether every `{placeholder}` in an output template is one of [TEMPLATE_PLACEHOLDERS], and
+/// the template is a path that stays inside the output directory
+fn is_valid_template(template: &str) -> bool {
+	if template.contains('\\') || template.starts_with('/') || template.split('/').any(|part| part.is_empty() || part == "..") {
+		return false
+	}
+	template.split('{')
+		.skip(1)
+		.all(|part| match part.split_once('}') {
+			Some((placeholder, _)) => TEMPLATE_PLACEHOLDERS.contains(&placeholder),
+			None => false
+		})
+}
+
+/// Whether a file name rendered from the output template stays inside the output directory: a
+/// relative path without empty, `.` or `..` parts. [is_valid_template] checks the template, but
+/// placeholders like `{date}` can still render to something that isn't.
+fn is_valid_file_name(file_name: &str) -> bool {
+	!file_name.contains('\\') && file_name.split('/').all(|part| !matches!(part, "" | "." | ".."))
+}
+
+/// Fill in the placeholders in an output template. `date` is `image_date` already formatted with
+/// --date-format, and `url_hash` is from [ImageProperties::url_hash].
+fn render_template(template: &str, resolution: &Resolution, image_date: NaiveDate, date: &str, url_hash: &str) -> String {
+	template
+		.replace("{resolution}", &resolution.to_string())
+		.replace("{urlhash}", url_hash)
+		.replace("{date}", date)
+		.replace("{year}", &image_date.format("%Y").to_string())
+		.replace("{month}", &image_date.format("%m").to_string())
+		.replace("{day}", &image_date.format("%d").to_string())
+}
+
+/// Image extensions that --normalize-extension replaces
+const IMAGE_EXTENSIONS: [&str; 8] = ["jpg", "jpeg", "jpe", "jfif", "png", "webp", "gif", "bmp"];
+
+/// `file_name` ending in `.jpg`, replacing its extension if it's one of [IMAGE_EXTENSIONS] in any
+/// case. Anything else after a dot is kept, since it's likely part of a date.
+fn normalize_extension(file_name: &str) -> String {
+	match file_name.rsplit_once('.') {
+		Some((stem, extension)) if IMAGE_EXTENSIONS.iter().any(|image| extension.eq_ignore_ascii_case(image)) => format!("{}.jpg", stem),
+		_ => format!("{}.jpg", file_name)
+	}
+}
+
+/// A piece of an output template, see [template_parts]
+enum TemplatePart<'a> {
+	Literal(&'a str),
+	/// The name of a placeholder, without the braces
+	Placeholder(&'a str)
+}
+
+/// Split a valid output template into its placeholders and the text in between
+fn template_parts(template: &str) -> Vec<TemplatePart<'_>> {
+	let mut parts = Vec::new();
+	let mut rest = template;
+	while let Some((literal, after)) = rest.split_once('{') {
+		if !literal.is_empty() {
+			parts.push(TemplatePart::Literal(literal));
+		}
+		// Valid templates close every placeholder
+		let (placeholder, after) = after.split_once('}').unwrap();
+		parts.push(TemplatePart::Placeholder(placeholder));
+		rest = after;
+	}
+	if !rest.is_empty() {
+		parts.push(TemplatePart::Literal(rest));
+	}
+	parts
+}
+
+/// Match a file name against the parts of an output template, the reverse of [render_template].
+/// The value of every placeholder is added to `values` if it matches.
+/// 
+/// Placeholders are tried from the shortest value to the longest, and have to look like what
+/// they're for (ex. `{resolution}` has to parse as a [Resolution]), which is enough to separate
+/// them in any template that can be told apart at all.
+fn match_template<'a>(parts: &[TemplatePart<'a>], name: &str, values: &mut Vec<(&'a str, String)>) -> bool {
+	match parts.split_first() {
+		None => name.is_empty(),
+		Some((TemplatePart::Literal(literal), rest)) => name.strip_prefix(literal).is_some_and(|name| match_template(rest, name, values)),
+		Some((TemplatePart::Placeholder(placeholder), rest)) => {
+			// No placeholder can produce a path separator
+			let longest = name.find('/').unwrap_or(name.len());
+			for end in (1..=longest).filter(|&end| name.is_char_boundary(end)) {
+				let value = &name[..end];
+				let plausible = match *placeholder {
+					"resolution" => value.parse::<Resolution>().is_ok(),
+					"year" => value.len() == 4 && value.bytes().all(|byte| byte.is_ascii_digit()),
+					"month" | "day" => value.len() == 2 && value.bytes().all(|byte| byte.is_ascii_digit()),
+					"urlhash" => value.len() == URL_HASH_LENGTH && value.bytes().all(|byte| byte.is_ascii_hexdigit()),
+					_ => true
+				};
+				if plausible && match_template(rest, &name[end..], values) {
+					values.push((placeholder, value.to_string()));
+					return true
+				}
+			}
+			false
+		}
+	}
+}
+
+/// An image found by [downloaded_files]
+struct ListedFile {
+	/// Path of the file, relative to the directory it's in
+	pub file_name: String,
+	/// The date from the file name, as it's written there if it can't be read back. Names without a
+	/// date get the date the file was last modified instead
+	pub date: Result<NaiveDate, Option<String>>,
+	/// Whether [ListedFile::date] is the modification date
+	pub modified_date: bool,
+	pub resolution: Option<Resolution>,
+	/// Size of the file in bytes
+	pub size: u64,
+	/// Whether the file decodes as an image
+	pub valid: bool
+}
+
+/// Every file in `dir` that matches --output-template, with what its name says about it.
+/// 
+/// Names are read with --date-format, so files named with a different one than the current one
+/// can't be dated, and are given the date as it's written in the name.
+fn downloaded_files(dir: &Path, config: &Config) -> std::io::Result<Vec<ListedFile>> {
+	let mut file_names = Vec::new();
+	files_in(dir, "", &mut file_names)?;
+	file_names.sort();
+	let parts = template_parts(&config.output_template);
+
+	let mut files = Vec::new();
+	for file_name in file_names {
+		let mut values = Vec::new();
+		if !match_template(&parts, &file_name, &mut values) {
+			continue
+		}
+		let value = |placeholder: &str| values.iter().find(|(name, _)| *name == placeholder).map(|(_, value)| value.as_str());
+		let date = match (value("date"), value("year"), value("month"), value("day")) {
+			(Some(date), ..) => NaiveDate::parse_from_str(date, &config.date_format).map_err(|_| Some(date.to_string())),
+			(None, Some(year), Some(month), Some(day)) => NaiveDate::parse_from_str(&format!("{}-{}-{}", year, month, day), "%Y-%m-%d")
+				.map_err(|_| Some(format!("{}-{}-{}", year, month, day))),
+			_ => Err(None)
+		};
+		let file_path = dir.join(&file_name);
+		let metadata = std::fs::metadata(&file_path)?;
+		// Templates like the default {resolution}.jpg are overwritten every day, so the file is from the day it was written
+		let modified_date = date == Err(None);
+		let date = match modified_date {
+			true => metadata.modified().map(|time| DateTime::<Local>::from(time).date_naive()).map_err(|_| None),
+			false => date
+		};
+		files.push(ListedFile {
+			date,
+			modified_date,
+			resolution: value("resolution").and_then(|resolution| resolution.parse().ok()),
+			size: metadata.len(),
+			valid: image::open(&file_path).is_ok(),
+			file_name
+		});
+	}
+	Ok(files)
+}
+
+/// Print a table of the images in `dir` that match --output-template, see the list-downloaded
+/// subcommand
+fn list_downloaded(dir: &Path, config: &Config) -> std::io::Result<()> {
+	println!("{:<12}{:<12}{:>14}  File", "Date", "Resolution", "Size (bytes)");
+	for file in downloaded_files(dir, config)? {
+		// Shown the same way no matter the --date-format, if it can be read back
+		let date = match &file.date {
+			Ok(date) => date.to_string(),
+			Err(text) => text.clone().unwrap_or_else(|| "-".to_string())
+		};
+		let resolution = file.resolution.map_or("-".to_string(), |resolution| resolution.to_string());
+		let mut notes = Vec::new();
+		if file.date.is_err() {
+			notes.push("can't tell its date");
+		}
+		if file.modified_date {
+			notes.push("dated by when it was modified");
+		}
+		if !file.valid {
+			notes.push("not a valid image");
+		}
+		let notes = match notes.is_empty() {
+			true => String::new(),
+			false => format!(" ({})", notes.join(", "))
+		};
+		println!("{:<12}{:<12}{:>14}  {}{}", date, resolution, file.size, file.file_name, notes);
+	}
+	Ok(())
+}
+
+/// How many days of images Bing's API can give at once, which is also as far back as it goes
+const BING_HISTORY_DAYS: u32 = 8;
+
+/// Replace the images in the -p directories that can't be decoded with new downloads, for
+/// --redownload-corrupt. Returns whether every one of them was replaced.
+/// 
+/// Only images Bing still has can be replaced, which are the ones in its history for the last
+/// [BING_HISTORY_DAYS] days.
+async fn redownload_corrupt(config: &Arc<Config>, markets: &[Option<String>], options: &RequestOptions) -> Result<bool, Box<dyn std::error::Error>> {
+	let mut all_repaired = true;
+	for market in markets {
+		let json = match fetch_metadata(market.as_deref(), BING_HISTORY_DAYS, options).await {
+			Ok(json) => json,
+			Err(error) => {
+				eprintln!("Error fetching metadata: {}", error);
+				return Ok(false)
+			}
+		};
+		let images: HashMap<NaiveDate, &JsonValue> = json["images"].as_array().into_iter().flatten().filter_map(|image| {
+			let date = NaiveDate::parse_from_str(image["startdate"].as_str()?, "%Y%m%d").ok()?;
+			image["url"].as_str().filter(|url| is_valid_image_url(url))?;
+			Some((date, image))
+		}).collect();
+
+		for path in &config.paths {
+			// With more than one market, each one has its own subdirectory
+			let dir = match market {
+				Some(market) if markets.len() > 1 => path.join(market),
+				_ => path.clone()
+			};
+			if !dir.is_dir() {
+				continue
+			}
+			for file in downloaded_files(&dir, config)?.into_iter().filter(|file| !file.valid) {
+				let file_path = dir.join(&file.file_name);
+				let (url, resolution, title) = match (&file.date, &file.resolution) {
+					(Ok(date), Some(resolution)) => {
+						let image = match images.get(date) {
+							// An image is up until the day after its date, so a file written the next day can still be it
+							None if file.modified_date => date.pred_opt().and_then(|day| images.get(&day)),
+							image => image
+						};
+						match image {
+							Some(image) => {
+								let urlbase = image_urlbase(image);
+								let url = image_url(image["url"].as_str().unwrap_or_default(), urlbase, resolution, config);
+								(url, resolution, image["title"].as_str().filter(|title| !title.is_empty()).unwrap_or(UNTITLED))
+							},
+							None => {
+								eprintln!("Can't repair {:?}: {} is outside of the {} days Bing has images for", file_path, date, BING_HISTORY_DAYS);
+								all_repaired = false;
+								continue
+							}
+						}
+					},
+					(Err(_), _) => {
+						eprintln!("Can't repair {:?}: can't tell which day's image it is", file_path);
+						all_repaired = false;
+						continue
+					},
+					(_, None) => {
+						eprintln!("Can't repair {:?}: its name doesn't have the resolution of the image", file_path);
+						all_repaired = false;
+						continue
+					}
+				};
+
+				let bytes = match get(&url, options, HeaderMap::new()).await {
+					Ok(response) => read_body(response, options).await.map_err(|error| error.to_string()),
+					Err(error) => Err(error.to_string())
+				};
+				// Filtered images are written under their own name with --keep-original, so this one is unfiltered
+				let filter_config = config.clone();
+				let title = title.to_string();
+				let bytes = match bytes {
+					Ok(bytes) if config.has_filters() && !config.keep_original => run_image_job(config, move || apply_filters(&bytes, &title, &filter_config)).await,
+					Ok(bytes) => run_image_job(config, move || image::load_from_memory(&bytes).map(|_| bytes)).await,
+					Err(error) => Err(error)
+				};
+				let written = bytes.and_then(|bytes| {
+					write_atomically(&file_path, config.fsync, config.temp_dir.as_deref(), &bytes).map_err(|error| error.to_string())
+				});
+				match written {
+					Ok(_) => status!("Repaired {:?} with the {} image", file_path, resolution),
+					Err(error) => {
+						eprintln!("Error repairing {:?}: {}", file_path, error);
+						all_repaired = false
+					}
+				}
+			}
+		}
+	}
+	Ok(all_repaired)
+}
+
+/// Add the paths of every file under `dir` to `files`, relative to it with `/` separators and
+/// starting with `prefix`
+fn files_in(dir: &Path, prefix: &str, files: &mut Vec<String>) -> std::io::Result<()> {
+	for entry in std::fs::read_dir(dir)? {
+		let entry = entry?;
+		let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+		if entry.file_type()?.is_dir() {
+			files_in(&entry.path(), &format!("{}/", name), files)?;
+		} else {
+			files.push(name);
+		}
+	}
+	Ok(())
+}
+
+/// Whether a strftime format is valid and safe to put in a file name
+fn is_valid_date_format(format: &str) -> bool {
+	if StrftimeItems::new(format).any(|item| item == Item::Error) {
+		return false
+	}
+	// Check an actual date too, since things like %D turn into separators
+	let sample = NaiveDate::from_ymd_opt(2000, 12, 31).unwrap().format(format).to_string();
+	!sample.contains(['/', '\\']) && !matches!(sample.as_str(), "" | "." | "..")
+}
+
+/// Validate that an argument is a whole number greater than zero
+fn at_least_one(value: &str) -> Result<(), String> {
+	match value.parse::<usize>() {
+		Ok(0) => Err("must be at least 1".to_string()),
+		Ok(_) => Ok(()),
+		Err(error) => Err(error.to_string())
+	}
+}
+
+/// Check files against the SHA-256 hashes listed in `manifest`, printing whether each one matched.
+/// 
+/// `manifest` is in the format sha256sum writes (`<hash>  <file>` per line) with paths relative to
+/// it, or just a hash for the file it's named after, ex. `1920x1080.jpg.sha256`. Returns whether
+/// every file matched.
+fn verify_checksums(manifest: &Path) -> std::io::Result<bool> {
+	let contents = std::fs::read_to_string(manifest)?;
+	let directory = manifest.parent().unwrap_or(manifest);
+	let mut all_matched = true;
+	for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+		let (expected, file_path) = match line.trim().split_once(char::is_whitespace) {
+			// sha256sum marks files hashed in binary mode with a *
+			Some((hash, name)) => (hash, directory.join(name.trim_start().trim_start_matches('*'))),
+			None => (line.trim(), manifest.with_extension(""))
+		};
+		match std::fs::read(&file_path) {
+			Ok(bytes) if sha256_hex(&bytes).eq_ignore_ascii_case(expected) => println!("{:?}: OK", file_path),
+			Ok(_) => {
+				println!("{:?}: FAILED", file_path);
+				all_matched = false;
+			},
+			Err(error) => {
+				eprintln!("Error reading file {:?}: {}", file_path, error);
+				all_matched = false;
+			}
+		}
+	}
+	Ok(all_matched)
+}
+
+/// Replace every `downloaded` file with a hard link to the first one with the same contents in the
+/// same directory, for --hardlink-duplicates. Prints how many bytes that saved in each directory.
+/// 
+/// Only files in the same directory are linked, since the -p directories can be on different
+/// filesystems.
+fn link_duplicates(paths: &[PathBuf], downloaded: &[Downloaded]) {
+	for path in paths {
+		let mut seen: HashMap<String, PathBuf> = HashMap::new();
+		let mut saved = 0;
+		for file in downloaded {
+			let file_path = path.join(&file.file_name);
+			let hash = match std::fs::read(&file_path) {
+				Ok(bytes) => sha256_hex(&bytes),
+				Err(error) => {
+					eprintln!("Error reading file {:?}: {}", file_path, error);
+					continue
+				}
+			};
+			let original = match seen.entry(hash) {
+				Entry::Occupied(original) => original.get().clone(),
+				Entry::Vacant(entry) => {
+					entry.insert(file_path);
+					continue
+				}
+			};
+			if is_same_file(&original, &file_path) {
+				continue
+			}
+			match link_atomically(&original, &file_path) {
+				Ok(_) => {
+					status!("Linked file {:?} to identical file {:?}", file_path, original);
+					saved += file.size;
+				},
+				Err(error) => eprintln!("Error linking file {:?} to {:?}: {}", file_path, original, error)
+			}
+		}
+		if saved > 0 {
+			status!("Saved {} bytes in {:?} with hard links", saved, path);
+		}
+	}
+}
+
+/// Whether `a` and `b` are already the same file, ex. linked by an earlier --hardlink-duplicates
+#[cfg(unix)]
+fn is_same_file(a: &Path, b: &Path) -> bool {
+	use std::os::unix::fs::MetadataExt;
+	match (std::fs::metadata(a), std::fs::metadata(b)) {
+		(Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+		_ => false
+	}
+}
+
+/// Whether `a` and `b` are already the same file. Always false, since other platforms don't have a
+/// stable way of telling
+#[cfg(not(unix))]
+fn is_same_file(_a: &Path, _b: &Path) -> bool {
+	false
+}
+
+/// Replace `file_path` with a hard link to `original`, without there being a moment where it's
+/// missing. Like [write_atomically], the link is made under a temporary name and renamed into place.
+fn link_atomically(original: &Path, file_path: &Path) -> std::io::Result<()> {
+	let temp_path = temp_path(file_path, None);
+
+	// hard_link won't replace one left over from an interrupted run
+	let _ = std::fs::remove_file(&temp_path);
+	let result = std::fs::hard_link(original, &temp_path).and_then(|_| std::fs::rename(&temp_path, file_path));
+	if result.is_err() {
+		let _ = std::fs::remove_file(&temp_path);
+	}
+	result
+}
+
+/// SHA-256 hash of `bytes`, as lowercase hex
+fn sha256_hex(bytes: &[u8]) -> String {
+	Sha256::digest(bytes).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// How many hex digits of the hash [ImageProperties::url_hash] keeps
+const URL_HASH_LENGTH: usize = 12;
+
+/// Whether `market` looks like a market code: a 2 or 3 letter language code and a 2 letter region
+/// code, ex. en-US
+fn is_valid_market(market: &str) -> bool {
+	match market.split_once('-') {
+		Some((language, region)) => (2..=3).contains(&language.len()) && language.bytes().all(|byte| byte.is_ascii_alphabetic())
+			&& region.len() == 2 && region.bytes().all(|byte| byte.is_ascii_alphabetic()),
+		None => false
+	}
+}
+
+/// Bing market codes and the regions they're for.
+/// 
+/// Not every market Bing has, just the common ones.
+const MARKETS: [(&str, &str); 13] = [
+	("de-DE", "Germany"),
+	("en-AU", "Australia"),
+	("en-CA", "Canada (English)"),
+	("en-GB", "United Kingdom"),
+	("en-IN", "India"),
+	("en-US", "United States"),
+	("es-ES", "Spain"),
+	("fr-CA", "Canada (French)"),
+	("fr-FR", "France"),
+	("it-IT", "Italy"),
+	("ja-JP", "Japan"),
+	("pt-BR", "Brazil"),
+	("zh-CN", "China")
+];
+
+/// Initial delay before retrying a request that failed because the network is unreachable
+const STARTUP_BACKOFF: Duration = Duration::from_secs(2);
+
+/// Initial delay before retrying a request that failed for any other reason. Each retry waits a
+/// random time up to the backoff, so requests that failed together don't all retry together.
+const RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Send a GET request to `url`, with `headers` on top of the client's defaults.
+/// 
+/// Requests that fail to connect (DNS not resolving yet, connection refused) are retried with
+/// an exponential backoff for as long as the grace period allows. Other errors and server errors
+/// are retried up to the number of retries, as long as the retry budget isn't used up, with a
+/// jittered exponential backoff.
+async fn get(url: &str, options: &RequestOptions, headers: HeaderMap) -> reqwest::Result<reqwest::Response> {
+	let start = Instant::now();
+	let mut backoff = STARTUP_BACKOFF;
+	let mut retries = 0;
+	let mut retry_backoff = RETRY_BACKOFF;
+	// Seeded separately for every request, so they don't share a sequence
+	let rng = fastrand::Rng::new();
+
+	loop {
+		if options.trace {
+			eprintln!("trace: GET {}", url);
+		}
+		let result = options.client.get(url).headers(headers.clone()).send().await;
+		if let (true, Ok(response)) = (options.trace, &result) {
+			let content_type = response.headers().get(header::CONTENT_TYPE)
+				.and_then(|value| value.to_str().ok())
+				.unwrap_or("none");
+			let content_length = response.content_length()
+				.map_or("none".to_string(), |length| length.to_string());
+			eprintln!("trace: {} {} (content-type: {}, content-length: {})", response.status(), url, content_type, content_length);
+		}
+
+		match &result {
+			Err(error) if error.is_connect() && start.elapsed() + backoff <= options.grace => {
+				eprintln!("Network unreachable, retrying \"{}\" in {}s: {}", url, backoff.as_secs(), error);
+				tokio::time::sleep(backoff).await;
+				backoff *= 2;
+				continue
+			},
+			Err(_) if retries < options.retries => (),
+			Ok(response) if retries < options.retries && response.status().is_server_error() => (),
+			_ => return result
+		}
+
+		if !options.take_retry() {
+			eprintln!("Retry budget used up, giving up on \"{}\"", url);
+			return result
+		}
+		retries += 1;
+		let reason = match &result {
+			Ok(response) => response.status().to_string(),
+			Err(error) => error.to_string()
+		};
+		let delay = retry_backoff.mul_f64(rng.f64());
+		eprintln!("Request failed, retrying \"{}\" in {:.1}s ({}/{}): {}", url, delay.as_secs_f64(), retries, options.retries, reason);
+		tokio::time::sleep(delay).await;
+		retry_backoff *= 2;
+	}
+}
+
+/// Market whose text is used by --title-fallback
+const FALLBACK_MARKET: &str = "en-US";
+
+/// Text fields that --title-fallback fills in
+const FALLBACK_FIELDS: [&str; 2] = ["title", "copyright"];
+
+/// Fill in missing or empty [FALLBACK_FIELDS] in each market's response with the ones from
+/// [FALLBACK_MARKET], which is only fetched if it's needed.
+/// 
+/// The text is only used if both responses are for the same image, going by `hsh` when both have
+/// it. Doesn't return an error, but will print any errors it gets to stderr.
+async fn fill_missing_text(responses: &mut [JsonValue], markets: &[Option<String>], source: &dyn Source, options: &RequestOptions) {
+	let mut fallback: Option<FetchResult> = None;
+	for (market, json) in markets.iter().zip(responses) {
+		let meta = match json.pointer_mut("/images/0") {
+			Some(meta) if market.as_deref() != Some(FALLBACK_MARKET) => meta,
+			_ => continue
+		};
+		let missing: Vec<&str> = FALLBACK_FIELDS.into_iter()
+			.filter(|&field| meta[field].as_str().is_none_or(str::is_empty))
+			.collect();
+		if missing.is_empty() {
+			continue
+		}
+
+		if fallback.is_none() {
+			// --save-response is for the markets that were asked for, so it isn't replaced with this one
+			let options = RequestOptions { save_response: None, ..options.clone() };
+			fallback = Some(source.fetch(Some(FALLBACK_MARKET), &options).await);
+		}
+		let fallback_meta = match fallback.as_ref().unwrap() {
+			Ok(json) => &json["images"][0],
+			Err(error) => {
+				eprintln!("Error fetching {} metadata for --title-fallback: {}", FALLBACK_MARKET, error);
+				return
+			}
+		};
+		if let (Some(hash), Some(fallback_hash)) = (meta["hsh"].as_str(), fallback_meta["hsh"].as_str()) {
+			if hash != fallback_hash {
+				eprintln!("{} has a different image than {}, not using its {}", FALLBACK_MARKET, market.as_deref().unwrap_or("the default market"), missing.join(" or "));
+				continue
+			}
+		}
+		for field in missing {
+			if let Some(text) = fallback_meta[field].as_str().filter(|text| !text.is_empty()) {
+				meta[field] = text.into();
+			}
+		}
+	}
+}
+
+/// Build the full URL of the image at `resolution` the way --url-strategy says to, from the `url`
+/// and `urlbase` fields of the API's response
+fn image_url(url: &str, urlbase: Option<&str>, resolution: &Resolution, config: &Config) -> String {
+	match urlbase {
+		// ex. /th?id=OHR.Example becomes /th?id=OHR.Example_1366x768.jpg with the default --url-template
+		Some(urlbase) if config.url_strategy == "urlbase" => absolute_url(&render_url_template(&config.url_template, urlbase, resolution)),
+		_ => build_image_url(url, resolution, &config.replace_token)
+	}
+}
+
+/// Placeholders that can be used in --url-template
+const URL_TEMPLATE_PLACEHOLDERS: [&str; 4] = ["base", "w", "h", "res"];
+
+/// Whether every `{placeholder}` in a URL template is one of [URL_TEMPLATE_PLACEHOLDERS]
+fn is_valid_url_template(template: &str) -> bool {
+	template.split('{')
+		.skip(1)
+		.all(|part| match part.split_once('}') {
+			Some((placeholder, _)) => URL_TEMPLATE_PLACEHOLDERS.contains(&placeholder),
+			None => false
+		})
+}
+
+/// Fill in the placeholders in a URL template with the API's `urlbase` and `resolution`
+fn render_url_template(template: &str, urlbase: &str, resolution: &Resolution) -> String {
+	template
+		.replace("{base}", urlbase)
+		.replace("{w}", &resolution.x.to_string())
+		.replace("{h}", &resolution.y.to_string())
+		.replace("{res}", &resolution.to_string())
+}
+
+/// Build the full URL of the image at `resolution` from the `url` field of the API's response.
+/// 
+/// The API's URL points at the 1920x1080 image, so every `token` (normally `1920x1080`) in it is
+/// replaced with the requested resolution, including any in the query string. A URL that doesn't
+/// contain `token` at all is used unchanged.
+fn build_image_url(url: &str, resolution: &Resolution, token: &str) -> String {
+	// Replace the resolution in the image path with our own
+	let url = url.replace(token, &resolution.to_string());
+	absolute_url(&url)
+}
+
+/// Whether `url` from an API response is something [absolute_url] can turn into a working URL: a
+/// path starting with `/`, or already absolute
+fn is_valid_image_url(url: &str) -> bool {
+	match url.strip_prefix("https://").or_else(|| url.strip_prefix("http://")) {
+		Some(rest) => !rest.is_empty() && !rest.starts_with('/'),
+		None => url.starts_with('/') && url.len() > 1
+	}
+}
+
+/// The `urlbase` field of `meta`, an image from the API's response, if it has one that
+/// [is_valid_image_url] accepts. Without one, URLs are built from its `url` instead, see [image_url].
+fn image_urlbase(meta: &JsonValue) -> Option<&str> {
+	meta["urlbase"].as_str().filter(|urlbase| is_valid_image_url(urlbase))
+}
+
+/// `url` from an API response as an absolute URL. Bing's are relative to bing.com, other sources'
+/// are already absolute.
+fn absolute_url(url: &str) -> String {
+	if url.starts_with("https://") || url.starts_with("http://") {
+		url.to_string()
+	} else {
+		format!("https://bing.com{}", url)
+	}
+}
+
+/// How much of a response that isn't JSON to show in the error message
+const BODY_PREVIEW_LENGTH: usize = 200;
+
+/// Fetch the API's description of today's image, and the `count - 1` days before it, for `market`
+/// if given.
+/// 
+/// If the response isn't JSON, which is what captive portals and misbehaving proxies tend to do,
+/// the error includes the start of the response so it's clear what came back instead.
+async fn fetch_metadata(market: Option<&str>, count: u32, options: &RequestOptions) -> FetchResult {
+	let mut url = format!("https://www.bing.com/HPImageArchive.aspx?format=js&idx=0&n={}", count);
+	if let Some(market) = market {
+		url = format!("{}&mkt={}", url, market);
+	}
+
+	let cached = options.cache.as_ref().and_then(|cache| cache.get(&url));
+	let mut headers = cached.as_ref().map(conditional_headers).unwrap_or_default();
+	// Titles and copyrights are localized by Accept-Language as well as by the market
+	if let Some(language) = market.and_then(accept_language) {
+		headers.insert(header::ACCEPT_LANGUAGE, language);
+	}
+
+	let response = get(&url, options, headers).await?;
+	if let (StatusCode::NOT_MODIFIED, Some(cached)) = (response.status(), &cached) {
+		// Serializing a JsonValue can't fail
+		save_response(&serde_json::to_vec(&cached["body"]).unwrap(), options);
+		return Ok(cached["body"].clone())
+	}
+	let headers = response.headers().clone();
+	let content_type = response.headers().get(header::CONTENT_TYPE)
+		.and_then(|value| value.to_str().ok())
+		.unwrap_or("no content type")
+		.to_string();
+	let body = read_body(response, options).await?;
+	save_response(&body, options);
+	check_strict_json(&body, &url, options)?;
+
+	match serde_json::from_slice(&body) {
+		Ok(json) => {
+			if let Some(cache) = &options.cache {
+				cache.store(&url, &headers, "body", &json);
+			}
+			Ok(json)
+		},
+		Err(error) if content_type.contains("json") => Err(format!("Can't parse metadata from \"{}\": {}", url, error).into()),
+		Err(_) => {
+			let preview = String::from_utf8_lossy(&body[..body.len().min(BODY_PREVIEW_LENGTH)]);
+			Err(format!("Expected JSON metadata from \"{}\" but got {}, starting with:\n{}", url, content_type, preview).into())
+		}
+	}
+}
+
+/// Write a metadata response to the path in [RequestOptions::save_response], if there is one.
+/// 
+/// `body` is written as it is, unless the response should be indented and is valid JSON. Doesn't
+/// return an error, since the response can still be used, but prints any errors it gets to stderr.
+fn save_response(body: &[u8], options: &RequestOptions) {
+	let file_path = match &options.save_response {
+		Some(file_path) => file_path,
+		None => return
+	};
+	let pretty = match options.pretty_response {
+		true => serde_json::from_slice::<JsonValue>(body).ok().and_then(|json| serde_json::to_vec_pretty(&json).ok()),
+		false => None
+	};
+	match std::fs::write(file_path, pretty.as_deref().unwrap_or(body)) {
+		Ok(_) => status!("Successfully written file {:?}", file_path),
+		Err(error) => eprintln!("Error writing file {:?}: {}", file_path, error)
+	}
+}
+
+/// With --strict-json, check that a metadata response from `url` has nothing after the JSON value
+/// at its start, not even the whitespace serde_json allows.
+/// 
+/// Bodies that don't start with JSON at all are left for parsing to report.
+fn check_strict_json(body: &[u8], url: &str, options: &RequestOptions) -> Result<(), String> {
+	if !options.strict_json {
+		return Ok(())
+	}
+	let mut values = serde_json::Deserializer::from_slice(body).into_iter::<serde::de::IgnoredAny>();
+	match values.next() {
+		Some(Ok(_)) if values.byte_offset() < body.len() => {
+			Err(format!("Unexpected data after the JSON metadata from \"{}\", starting at byte {}", url, values.byte_offset()))
+		},
+		_ => Ok(())
+	}
+}
+
+/// Read the whole body of `response`, giving up if it goes longer than the read timeout in between
+/// chunks.
+/// 
+/// reqwest's own timeout covers the entire request, which would cut off large downloads that are
+/// slow but still progressing.
+/// 
+/// Bodies that end before the response's Content-Length, if it has one, are a [TruncatedBody]
+/// error.
+async fn read_body(mut response: reqwest::Response, options: &RequestOptions) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+	let expected = response.content_length();
+	let mut body = Vec::new();
+	loop {
+		let chunk = match options.read_timeout {
+			Some(timeout) => tokio::time::timeout(timeout, response.chunk()).await
+				.map_err(|_| format!("no data received for {} seconds", timeout.as_secs()))?,
+			None => response.chunk().await
+		};
+		// The connection closing early is an error too, which is the same truncation
+		let truncated = |received: usize| expected
+			.filter(|&expected| received as u64 != expected)
+			.map(|expected| TruncatedBody { received: received as u64, expected });
+		match chunk {
+			Ok(Some(chunk)) => {
+				if let Some(rate_limit) = &options.rate_limit {
+					rate_limit.take(chunk.len()).await;
+				}
+				body.extend_from_slice(&chunk)
+			},
+			Ok(None) => return match truncated(body.len()) {
+				Some(truncated) => Err(truncated.into()),
+				None => Ok(body)
+			},
+			Err(error) if error.is_body() || error.is_decode() => return Err(match truncated(body.len()) {
+				Some(truncated) => truncated.into(),
+				None => error.into()
+			}),
+			Err(error) => return Err(error.into())
+		}
+	}
+}
+
+/// Error from [read_body] for a response that's a different length than its Content-Length
+#[derive(Debug)]
+struct TruncatedBody {
+	pub received: u64,
+	pub expected: u64
+}
+
+impl std::fmt::Display for TruncatedBody {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(f, "download was cut off at {} of {} bytes", self.received, self.expected)
+	}
+}
+
+impl std::error::Error for TruncatedBody {}
+
+/// [read_body] for an image from `url`. With --retry-on-partial, it's downloaded again when it's
+/// a [TruncatedBody], up to the number of retries (at least once) and as long as the retry budget
+/// isn't used up.
+async fn read_image(mut response: reqwest::Response, url: &str, options: &RequestOptions) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+	let max_retries = options.retries.max(1);
+	let mut retries = 0;
+	loop {
+		let error = match read_body(response, options).await {
+			Err(error) if options.retry_on_partial && retries < max_retries && error.is::<TruncatedBody>() => error,
+			result => return result
+		};
+		if !options.take_retry() {
+			eprintln!("Retry budget used up, giving up on \"{}\"", url);
+			return Err(error)
+		}
+		retries += 1;
+		eprintln!("Download of \"{}\" failed, retrying ({}/{}): {}", url, retries, max_retries, error);
+		response = get(url, options, HeaderMap::new()).await?.error_for_status()?;
+	}
+}
+
+/// The `url` field of the API's response with only the image's `id` left in its query, ex.
+/// `/th?id=OHR.Example_1920x1080.jpg&rf=LaDigue_1920x1080.jpg&pid=hp` becomes
+/// `/th?id=OHR.Example_1920x1080.jpg`.
+fn strip_query(url: &str) -> String {
+	match url.split_once('?') {
+		Some((path, query)) => match query.split('&').find(|param| param.starts_with("id=")) {
+			Some(id) => format!("{}?{}", path, id),
+			None => path.to_string()
+		},
+		None => url.to_string()
+	}
+}
+
+/// An Accept-Language header asking for the language of `market`, ex. `de-DE,de;q=0.9` for de-DE
+fn accept_language(market: &str) -> Option<HeaderValue> {
+	let language = market.split('-').next().unwrap_or(market);
+	HeaderValue::from_str(&format!("{},{};q=0.9", market, language)).ok()
+}
+
+/// Names of the [Source]s that can be picked with --source
+const SOURCES: &[&str] = &[
+	"bing",
+	#[cfg(feature = "peapix")]
+	"peapix",
+	#[cfg(feature = "spotlight")]
+	"spotlight"
+];
+
+/// What [Source::fetch] resolves to
+type FetchResult = Result<JsonValue, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Somewhere images can be fetched from.
+/// 
+/// Every source gives back metadata in the shape of Bing's API response, so nothing after fetching
+/// needs to know where the image came from.
+/// 
+/// That's the raw JSON rather than parsed [ImageProperties], because a lot happens to the response
+/// before it's parsed: --save-response writes it as-is, --validate-schema and --check check it,
+/// --title-fallback fills in its gaps, and --only-new-image compares its `hsh`. Parsing it also
+/// depends on the run's config (--date-format, which resolutions there are), not just the source.
+/// The cost is that other sources have to build a Bing-shaped response, like peapix's does.
+trait Source: Send + Sync {
+	/// Fetch the metadata of today's image, for `market` if given
+	fn fetch<'a>(&'a self, market: Option<&'a str>, options: &'a RequestOptions) -> Pin<Box<dyn Future<Output = FetchResult> + Send + 'a>>;
+}
+
+/// Bing's own API, see [fetch_metadata]
+struct BingSource;
+
+impl Source for BingSource {
+	fn fetch<'a>(&'a self, market: Option<&'a str>, options: &'a RequestOptions) -> Pin<Box<dyn Future<Output = FetchResult> + Send + 'a>> {
+		Box::pin(fetch_metadata(market, 1, options))
+	}
+}
+
+/// peapix's archive of Bing's images, see [fetch_peapix]
+#[cfg(feature = "peapix")]
+struct PeapixSource;
+
+#[cfg(feature = "peapix")]
+impl Source for PeapixSource {
+	fn fetch<'a>(&'a self, market: Option<&'a str>, options: &'a RequestOptions) -> Pin<Box<dyn Future<Output = FetchResult> + Send + 'a>> {
+		Box::pin(fetch_peapix(market, options))
+	}
+}
+
+/// Windows Spotlight's lock screen images, see [fetch_spotlight]
+#[cfg(feature = "spotlight")]
+struct SpotlightSource;
+
+#[cfg(feature = "spotlight")]
+impl Source for SpotlightSource {
+	fn fetch<'a>(&'a self, market: Option<&'a str>, options: &'a RequestOptions) -> Pin<Box<dyn Future<Output = FetchResult> + Send + 'a>> {
+		Box::pin(fetch_spotlight(market, options))
+	}
+}
+
+/// The [Source] called `name`, which is one of [SOURCES]
+fn source_named(name: &str) -> Arc<dyn Source> {
+	match name {
+		#[cfg(feature = "peapix")]
+		"peapix" => Arc::new(PeapixSource),
+		#[cfg(feature = "spotlight")]
+		"spotlight" => Arc::new(SpotlightSource),
+		_ => Arc::new(BingSource)
+	}
+}
+
+/// Fetch the latest image from peapix's archive of Bing images, for the country of `market` if given.
+/// 
+/// The feed goes back further than Bing's API, but only its first and latest image is used, since
+/// nothing else asks for older images. The response is converted into the shape of Bing's, so
+/// nothing else needs to know where the image came from. peapix's image URLs are absolute and only
+/// point at one size.
+#[cfg(feature = "peapix")]
+async fn fetch_peapix(market: Option<&str>, options: &RequestOptions) -> FetchResult {
+	let mut url = "https://peapix.com/bing/feed".to_string();
+	// peapix goes by country instead of market, ex. en-GB is gb
+	if let Some(country) = market.and_then(|market| market.split('-').nth(1)) {
+		url = format!("{}?country={}", url, country.to_lowercase());
+	}
+
+	let response = get(&url, options, HeaderMap::new()).await?.error_for_status()?;
+	let body = read_body(response, options).await?;
+	save_response(&body, options);
+	check_strict_json(&body, &url, options)?;
+	let feed: JsonValue = serde_json::from_slice(&body)
+		.map_err(|error| format!("Can't parse metadata from \"{}\": {}", url, error))?;
+	let image = feed.as_array()
+		.and_then(|images| images.first())
+		.ok_or_else(|| format!("No images in the feed from \"{}\"", url))?;
+
+	Ok(serde_json::json!({
+		"images": [{
+			"url": image["fullUrl"],
+			"title": image["title"],
+			"copyright": image["copyright"],
+			"startdate": image["date"].as_str().map(|date| date.replace('-', ""))
+		}]
+	}))
+}
+
+/// Market Spotlight is asked for when there isn't one, since it needs a country and a locale
+#[cfg(feature = "spotlight")]
+const SPOTLIGHT_DEFAULT_MARKET: &str = "en-US";
+
+/// Fetch an image from Windows Spotlight, the lock screen images, for `market` if given.
+/// 
+/// Spotlight's API returns a batch of images picked for the request instead of one image of the
+/// day, so this asks for a batch of one. Each image in the batch is a JSON string of its own, with
+/// a single 3840x2160 landscape URL and no date, so today's date is used. The response is converted
+/// into the shape of Bing's.
+#[cfg(feature = "spotlight")]
+async fn fetch_spotlight(market: Option<&str>, options: &RequestOptions) -> FetchResult {
+	let market = market.unwrap_or(SPOTLIGHT_DEFAULT_MARKET);
+	let country = market.split('-').nth(1).unwrap_or(market);
+	let url = format!("https://fd.api.iris.microsoft.com/v4/api/selection?placement=88000820&bcnt=1&country={}&locale={}&fmt=json", country, market);
+
+	let response = get(&url, options, HeaderMap::new()).await?.error_for_status()?;
+	let body = read_body(response, options).await?;
+	save_response(&body, options);
+	check_strict_json(&body, &url, options)?;
+	let batch: JsonValue = serde_json::from_slice(&body)
+		.map_err(|error| format!("Can't parse metadata from \"{}\": {}", url, error))?;
+	let item: JsonValue = batch["batchrsp"]["items"][0]["item"].as_str()
+		.and_then(|item| serde_json::from_str(item).ok())
+		.ok_or_else(|| format!("No images in the batch from \"{}\"", url))?;
+	let ad = &item["ad"];
+
+	Ok(serde_json::json!({
+		"images": [{
+			"url": ad["landscapeImage"]["asset"],
+			"title": ad["title"],
+			"copyright": ad["copyright"],
+			"startdate": Local::now().format("%Y%m%d").to_string()
+		}]
+	}))
+}
+
+/// Exit code for --fail-if-empty-archive, when the API works but has no images
+const EMPTY_FEED_EXIT_CODE: i32 = 3;
+
+/// Whether an API response has a list of images, but it's empty
+fn is_empty_feed(json: &JsonValue) -> bool {
+	json["images"].as_array().is_some_and(Vec::is_empty)
+}
+
+/// The shape of the API's response that bingimage expects, as a JSON Schema. Only `type`,
+/// `required`, `properties`, `items` and `minItems` are supported, see [check_schema].
+const METADATA_SCHEMA: &str = r#"{
+	"type": "object",
+	"required": ["images"],
+	"properties": {
+		"images": {
+			"type": "array",
+			"minItems": 1,
+			"items": {
+				"type": "object",
+				"required": ["url", "urlbase", "title", "copyright", "startdate"],
+				"properties": {
+					"url": { "type": "string" },
+					"urlbase": { "type": "string" },
+					"title": { "type": "string" },
+					"copyright": { "type": "string" },
+					"startdate": { "type": "string" }
+				}
+			}
+		}
+	}
+}"#;
+
+/// Whether the API's response matches [METADATA_SCHEMA], printing everything that doesn't to stderr
+fn matches_schema(json: &JsonValue) -> bool {
+	// The schema is a constant, so it always parses
+	let schema: JsonValue = serde_json::from_str(METADATA_SCHEMA).unwrap();
+	let mut errors = Vec::new();
+	check_schema(json, &schema, "response", &mut errors);
+	for error in &errors {
+		eprintln!("Metadata doesn't match the expected schema: {}", error);
+	}
+	errors.is_empty()
+}
+
+/// Add everything in `value` that doesn't match `schema` to `errors`, each starting with where it is in
+/// the response, ex. `response.images[0].url`.
+fn check_schema(value: &JsonValue, schema: &JsonValue, path: &str, errors: &mut Vec<String>) {
+	if let Some(expected) = schema["type"].as_str() {
+		let matches = match expected {
+			"object" => value.is_object(),
+			"array" => value.is_array(),
+			"string" => value.is_string(),
+			"number" => value.is_number(),
+			"integer" => value.is_i64() || value.is_u64(),
+			"boolean" => value.is_boolean(),
+			"null" => value.is_null(),
+			_ => true
+		};
+		if !matches {
+			errors.push(format!("{} should be of type {}", path, expected));
+			return
+		}
+	}
+
+	for field in schema["required"].as_array().into_iter().flatten().filter_map(JsonValue::as_str) {
+		if value.get(field).is_none() {
+			errors.push(format!("{}.{} is missing", path, field));
+		}
+	}
+	if let Some(properties) = schema["properties"].as_object() {
+		for (field, property) in properties {
+			if let Some(value) = value.get(field) {
+				check_schema(value, property, &format!("{}.{}", path, field), errors);
+			}
+		}
+	}
+
+	if let Some(items) = value.as_array() {
+		if let Some(min) = schema["minItems"].as_u64().filter(|&min| (items.len() as u64) < min) {
+			errors.push(format!("{} should have at least {} items", path, min));
+		}
+		for (index, item) in items.iter().enumerate() {
+			check_schema(item, &schema["items"], &format!("{}[{}]", path, index), errors);
+		}
+	}
+}
+
+/// Whether the API's response has at least one image, with a URL and title
+fn is_valid_metadata(json: &JsonValue) -> bool {
+	match json["images"].as_array().and_then(|images| images.first()) {
+		Some(image) => ["url", "title"].iter()
+			.all(|field| image[field].as_str().is_some_and(|value| !value.is_empty())),
+		None => false
+	}
+}
+
+/// Smallest an image can be for [head_check] to consider it real. Bing serves a tiny placeholder for
+/// some images it doesn't have instead of an error.
+const MIN_IMAGE_SIZE: u64 = 1024;
+
+/// What a HEAD request says about whether a URL has an image, see [head_status]
+enum HeadStatus {
+	/// A successful response, with its length if it has one
+	Image(Option<u64>),
+	NotFound,
+	/// A successful response that's shorter than [MIN_IMAGE_SIZE]
+	TooSmall(u64),
+	/// The request failed or got some other status, which doesn't say either way
+	Unknown(String)
+}
+
+/// Send a HEAD request to `url`, and tell from its status and length whether there's an image there.
+async fn head_status(url: &str, options: &RequestOptions) -> HeadStatus {
+	if options.trace {
+		eprintln!("trace: HEAD {}", url);
+	}
+	let response = match options.client.head(url).send().await {
+		Ok(response) => response,
+		Err(error) => return HeadStatus::Unknown(error.to_string())
+	};
+	if options.trace {
+		eprintln!("trace: {} {}", response.status(), url);
+	}
+
+	// HEAD responses have no body, so the length has to come from the header itself
+	let length = response.headers().get(header::CONTENT_LENGTH)
+		.and_then(|value| value.to_str().ok())
+		.and_then(|value| value.parse::<u64>().ok());
+	match (response.status(), length) {
+		(StatusCode::NOT_FOUND, _) => HeadStatus::NotFound,
+		(status, Some(length)) if status.is_success() && length < MIN_IMAGE_SIZE => HeadStatus::TooSmall(length),
+		(status, length) if status.is_success() => HeadStatus::Image(length),
+		(status, _) => HeadStatus::Unknown(status.to_string())
+	}
+}
+
+/// Whether `url` looks like it has an image, going by the status and length of a HEAD request.
+/// 
+/// Prints why to stderr if it doesn't. Servers that don't support HEAD, or where the request fails,
+/// get the benefit of the doubt, and the image is just downloaded to find out.
+async fn head_check(url: &str, options: &RequestOptions) -> bool {
+	match head_status(url, options).await {
+		HeadStatus::NotFound => {
+			eprintln!("Skipping \"{}\": not found", url);
+			false
+		},
+		HeadStatus::TooSmall(length) => {
+			eprintln!("Skipping \"{}\": only {} bytes, too small to be an image", url, length);
+			false
+		},
+		HeadStatus::Image(_) | HeadStatus::Unknown(_) => true
+	}
+}
+
+/// Resolutions that --probe-supported-resolutions tries on top of [SUPPORTED_RESOLUTIONS]: common
+/// screen sizes that Bing might have started serving
+const PROBE_RESOLUTIONS: [(u16, u16); 10] = [
+	(2560, 1600), (2560, 1440), (1680, 1050), (1600, 900), (1440, 900),
+	(1280, 800), (1280, 720), (1024, 600), (1440, 2560), (1080, 2340)
+];
+
+/// Send a HEAD request for today's image in every one of [SUPPORTED_RESOLUTIONS] and
+/// [PROBE_RESOLUTIONS], and print which ones Bing has, for --probe-supported-resolutions.
+/// 
+/// Returns whether the metadata could be fetched for every market.
+async fn probe_resolutions(config: &Config, markets: &[Option<String>], options: &RequestOptions) -> bool {
+	let candidates: Vec<Resolution> = SUPPORTED_RESOLUTIONS.into_iter()
+		.chain(PROBE_RESOLUTIONS)
+		.map(Resolution::from)
+		.collect();
+	for market in markets {
+		if markets.len() > 1 {
+			println!("{}:", market.as_deref().unwrap_or_default());
+		}
+		let json = match fetch_metadata(market.as_deref(), 1, options).await {
+			Ok(json) => json,
+			Err(error) => {
+				eprintln!("Error fetching metadata: {}", error);
+				return false
+			}
+		};
+		let image = &json["images"][0];
+		let url = image["url"].as_str().unwrap_or_default();
+		let urlbase = image_urlbase(image);
+
+		let (mut missing, mut unlisted) = (Vec::new(), Vec::new());
+		println!("{:<11}{:<8}Result", "Resolution", "Listed");
+		for resolution in &candidates {
+			let listed = resolution.is_supported();
+			let (found, result) = match head_status(&image_url(url, urlbase, resolution, config), options).await {
+				HeadStatus::Image(Some(length)) => (true, format!("found, {} bytes", length)),
+				HeadStatus::Image(None) => (true, "found".to_string()),
+				HeadStatus::NotFound => (false, "not found".to_string()),
+				HeadStatus::TooSmall(length) => (false, format!("placeholder, only {} bytes", length)),
+				HeadStatus::Unknown(reason) => (false, format!("unknown: {}", reason))
+			};
+			match (listed, found) {
+				(true, false) => missing.push(resolution.to_string()),
+				(false, true) => unlisted.push(resolution.to_string()),
+				_ => ()
+			}
+			println!("{:<11}{:<8}{}", resolution.to_string(), if listed { "yes" } else { "no" }, result);
+		}
+		if !missing.is_empty() {
+			println!("Listed as supported but not found: {}", missing.join(", "));
+		}
+		if !unlisted.is_empty() {
+			println!("Found but not listed as supported: {}", unlisted.join(", "));
+		}
+	}
+	true
+}
+
+/// Download the image with [ImageProperties] from `url`, built with [build_image_url], to a
+/// specified [Output] as `file_name`.
+/// 
+/// Prints any errors it gets to stderr as well as returning them. Returns what was written if it
+/// succeeded. `writing` is held for reading while the files are written, and there's nothing to
+/// wait on after that, so cancelling the download once the lock is taken can't leave a file behind
+/// that isn't returned.
+async fn download(properties: ImageProperties, url: String, output: Arc<Output>, file_name: String, config: Arc<Config>, options: RequestOptions, writing: &tokio::sync::RwLock<()>) -> Result<Downloaded, Failed> {
+	let started = Instant::now();
+	let failed = |reason: String| Failed { file_name: file_name.clone(), reason };
+	if options.head_check && !head_check(&url, &options).await {
+		return Err(failed("skipped by --head-check".to_string()))
+	}
+
+	// The cached validators only help if the files they're for are still there
+	let cached = match (&options.cache, &*output) {
+		(Some(cache), Output::Directories(paths)) => cache.get(&url).filter(|cached| {
+			cached["path"].as_str() == paths[0].join(&file_name).to_str()
+				&& paths.iter().all(|path| path.join(&file_name).is_file())
+		}),
+		_ => None
+	};
+	let conditional = cached.as_ref().map(conditional_headers).unwrap_or_default();
+	let image = get(&url, &options, conditional).await;
+
+	match image {
+		Ok(response) if cached.is_some() && response.status() == StatusCode::NOT_MODIFIED => {
+			let file_path = PathBuf::from(cached.unwrap()["path"].as_str().unwrap_or_default());
+			status!("Not modified, keeping file {:?}", file_path);
+			let size = std::fs::metadata(&file_path).map_or(0, |metadata| metadata.len());
+			Ok(Downloaded { resolution: properties.resolution, file_name, size, duration: started.elapsed() })
+		},
+		Ok(response) => {
+			let headers = response.headers().clone();
+			match read_image(response, &url, &options).await {
+				Ok(bytes) => {
+					// What to write: the image and, with --keep-original, its filtered variant
+					let mut files = vec![(file_name.clone(), bytes)];
+					if config.has_filters() {
+						let original = match config.keep_original {
+							true => files[0].1.clone(),
+							false => files.pop().unwrap().1
+						};
+						let filter_config = config.clone();
+						let title = properties.title.clone();
+						let filtered = match run_image_job(&config, move || apply_filters(&original, &title, &filter_config)).await {
+							Ok(filtered) => filtered,
+							Err(error) => {
+								eprintln!("Error applying filters to \"{}\": {}", url, error);
+								return Err(failed(format!("error applying filters: {}", error)))
+							}
+						};
+						match config.keep_original {
+							true => files.push((variant_file_name(&file_name, &config.filter_name()), filtered)),
+							false => files.push((file_name.clone(), filtered))
+						}
+					}
+					let size = files[0].1.len() as u64;
+					let _writing = writing.read().await;
+					let paths = match &*output {
+						Output::Archive(archive) => {
+							// JPEGs are already compressed, so they're stored as-is
+							let all_added = files.iter().all(|(name, bytes)| archive.add(name, bytes, CompressionMethod::Stored));
+							return match all_added {
+								true => Ok(Downloaded { resolution: properties.resolution, file_name, size, duration: started.elapsed() }),
+								false => Err(failed("couldn't add it to the archive".to_string()))
+							}
+						},
+						Output::Directories(paths) => paths
+					};
+
+					// Directories can be on different disks, so they're all written at once
+					let files = Arc::new(files);
+					let writes: Vec<_> = paths.iter().map(|path| {
+						let (path, files, fsync, temp_dir) = (path.clone(), files.clone(), config.fsync, config.temp_dir.clone());
+						tokio::task::spawn_blocking(move || files.iter().map(|(name, bytes)| {
+							let file_path = path.join(name);
+							let written = write_atomically(&file_path, fsync, temp_dir.as_deref(), bytes);
+							(file_path, written)
+						}).collect::<Vec<_>>())
+					}).collect();
+
+					// Every write is waited for, so one directory failing doesn't leave another half done
+					let mut write_error = None;
+					for (index, write) in writes.into_iter().enumerate() {
+						let results = match write.await {
+							Ok(results) => results,
+							Err(error) => {
+								eprintln!("Error writing to {:?}: {}", paths[index], error);
+								write_error.get_or_insert(format!("error writing to {:?}: {}", paths[index], error));
+								continue
+							}
+						};
+						for ((name, _), (file_path, written)) in files.iter().zip(results) {
+							match written {
+								Ok(_) => {
+									status!("Successfully written file {:?}", file_path);
+									// Only the first directory's copy of the main file is kept track of
+									if let (Some(cache), Some(file_path), 0, true) = (&options.cache, file_path.to_str(), index, *name == file_name) {
+										cache.store(&url, &headers, "path", &file_path.into());
+									}
+								},
+								Err(error) => {
+									eprintln!("Error writing file {:?}: {}", file_path, error);
+									write_error.get_or_insert(format!("error writing file {:?}: {}", file_path, error));
+								}
+							}
+						}
+					}
+					match write_error {
+						Some(reason) => Err(failed(reason)),
+						None => Ok(Downloaded { resolution: properties.resolution, file_name, size, duration: started.elapsed() })
+					}
+				},
+				Err(error) => {
+					eprintln!("Error downloading from \"{}\": {}", url, error);
+					Err(failed(error.to_string()))
+				}
+			}
+		},
+		Err(error) => {
+			eprintln!("Error downloading from \"{}\": {}", url, error);
+			Err(failed(error.to_string()))
+		}
+	}
+}
+
+/// Flush `file` to disk, unless `fsync` is off.
+/// 
+/// Filesystems that can't sync at all (some network mounts) only get a warning, since the data was
+/// still written.
+fn sync(file: &File, file_path: &std::path::Path, fsync: bool) -> std::io::Result<()> {
+	if !fsync {
+		return Ok(())
+	}
+	match file.sync_all() {
+		Err(error) if matches!(error.kind(), std::io::ErrorKind::Unsupported | std::io::ErrorKind::InvalidInput) => {
+			eprintln!("Warning: couldn't flush file {:?} to disk: {}", file_path, error);
+			Ok(())
+		},
+		result => result
+	}
+}
+
+/// Write all of `bytes` to `writer`, for [write_atomically].
+/// 
+/// `write` can take fewer bytes than it's given, so this keeps going until everything is written.
+/// A writer that stops taking any is an error saying how much was written, rather than a file
+/// that's silently cut off.
+fn write_fully<W: Write>(writer: &mut W, bytes: &[u8]) -> std::io::Result<()> {
+	let mut written = 0;
+	while written < bytes.len() {
+		match writer.write(&bytes[written..]) {
+			Ok(0) => return Err(std::io::Error::new(std::io::ErrorKind::WriteZero, format!("only wrote {} of {} bytes", written, bytes.len()))),
+			Ok(len) => written += len,
+			Err(error) if error.kind() == std::io::ErrorKind::Interrupted => (),
+			Err(error) => return Err(error)
+		}
+	}
+	writer.flush()
+}
+
+/// Write `bytes` to a file at `file_path`, so other processes never see it partially written.
+/// 
+/// It's written to a hidden temporary file (see [temp_path]) with [write_fully] first, which is
+/// synced (see [sync]) and then renamed into place. The temporary file is removed if anything
+/// fails. If `temp_dir` is on another filesystem, the file is copied into place instead, which
+/// isn't atomic.
+fn write_atomically(file_path: &std::path::Path, fsync: bool, temp_dir: Option<&Path>, bytes: &[u8]) -> std::io::Result<()> {
+	// Runs that were killed partway through can leave their temporary files behind
+	for dir in file_path.parent().into_iter().chain(temp_dir) {
+		remove_stale_temp_files(dir);
+	}
+	let temp_path = temp_path(file_path, temp_dir);
+	let result = File::create(&temp_path)
+		.and_then(|mut file| {
+			write_fully(&mut file, bytes)?;
+			sync(&file, file_path, fsync)
+		})
+		.and_then(|_| match std::fs::rename(&temp_path, file_path) {
+			Err(error) if error.kind() == std::io::ErrorKind::CrossesDevices => {
+				std::fs::copy(&temp_path, file_path).and_then(|_| std::fs::remove_file(&temp_path))
+			},
+			result => result
+		});
+	if result.is_err() {
+		let _ = std::fs::remove_file(&temp_path);
+	}
+	result
+}
+
+/// Counts the temporary files made by this run, so ones with the same name don't collide
+static TEMP_FILES: AtomicU32 = AtomicU32::new(0);
+
+/// Marks temporary files as bingimage's, so [remove_stale_temp_files] never touches anyone else's
+const TEMP_MARKER: &str = ".bingimage-";
+
+/// Where [write_atomically] writes `file_path` before it's done: a hidden file named like
+/// `.name.bingimage-<pid>-<n>.tmp`, unique to this run, next to it or in `temp_dir`
+fn temp_path(file_path: &Path, temp_dir: Option<&Path>) -> PathBuf {
+	let mut temp_name = std::ffi::OsString::from(".");
+	temp_name.push(file_path.file_name().unwrap_or_default());
+	temp_name.push(format!("{}{}-{}.tmp", TEMP_MARKER, std::process::id(), TEMP_FILES.fetch_add(1, Ordering::Relaxed)));
+	match temp_dir {
+		Some(temp_dir) => temp_dir.join(temp_name),
+		None => file_path.with_file_name(temp_name)
+	}
+}
+
+/// Whether `name` is one of [temp_path]'s temporary file names
+fn is_temp_name(name: &str) -> bool {
+	name.starts_with('.') && name.strip_suffix(".tmp")
+		.and_then(|name| name.rsplit_once(TEMP_MARKER))
+		.and_then(|(_, id)| id.split_once('-'))
+		.is_some_and(|(pid, count)| {
+			!pid.is_empty() && !count.is_empty() && pid.chars().chain(count.chars()).all(|c| c.is_ascii_digit())
+		})
+}
+
+/// How old a temporary file from [write_atomically] has to be for [remove_stale_temp_files] to
+/// count it as left over from a run that was killed, rather than one that's still going
+const STALE_TEMP_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// Directories [remove_stale_temp_files] has already cleaned up during this run
+static CLEANED_DIRS: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+/// Delete the temporary files from [write_atomically] directly in `dir` that are older than
+/// [STALE_TEMP_AGE], the first time it's called for `dir`. Only names [is_temp_name] matches are
+/// removed. Errors removing files are printed to stderr.
+fn remove_stale_temp_files(dir: &Path) {
+	if !CLEANED_DIRS.lock().unwrap().insert(dir.to_path_buf()) {
+		return
+	}
+	let entries = match std::fs::read_dir(dir) {
+		Ok(entries) => entries,
+		Err(_) => return
+	};
+	for entry in entries.flatten() {
+		let file_path = entry.path();
+		let is_temp = entry.file_name().to_str().is_some_and(is_temp_name);
+		let is_stale = entry.metadata().and_then(|metadata| metadata.modified()).ok()
+			.and_then(|modified| modified.elapsed().ok())
+			.is_some_and(|age| age >= STALE_TEMP_AGE);
+		if is_temp && is_stale && entry.file_type().is_ok_and(|file_type| file_type.is_file()) {
+			match std::fs::remove_file(&file_path) {
+				Ok(_) => status!("Removed leftover temporary file {:?}", file_path),
+				Err(error) => eprintln!("Error removing file {:?}: {}", file_path, error)
+			}
+		}
+	}
+}
+
+/// Whether `a` and `b` are on the same filesystem, so files can be renamed from one to the other
+#[cfg(unix)]
+fn is_same_filesystem(a: &Path, b: &Path) -> bool {
+	use std::os::unix::fs::MetadataExt;
+	match (std::fs::metadata(a), std::fs::metadata(b)) {
+		(Ok(a), Ok(b)) => a.dev() == b.dev(),
+		_ => true
+	}
+}
+
+/// Whether `a` and `b` are on the same filesystem. Always true, since other platforms don't have a
+/// simple way of telling
+#[cfg(not(unix))]
+fn is_same_filesystem(_a: &Path, _b: &Path) -> bool {
+	true
+}
+
+/// Hidden comment written into README.md, so it can be told apart from a README the user wrote
+const METADATA_MARKER: &str = "<!-- generated by bingimage -->";
+
+/// POST a notification about the run to a webhook.
+/// 
+/// Doesn't return an error, but will print a warning to stderr if it fails.
+async fn notify(webhook: &str, images: Vec<JsonValue>, downloaded: &[Downloaded], output: &Output, color: Option<&str>, options: &RequestOptions) {
+	let files: Vec<String> = downloaded.iter()
+		.map(|file| match output {
+			Output::Directories(paths) => paths[0].join(&file.file_name).to_string_lossy().into_owned(),
+			Output::Archive(_) => file.file_name.clone()
+		})
+		.collect();
+	let message = images.iter()
+		.map(|image| match image["copyright"].as_str() {
+			Some(copyright) => format!("{} | {}", image["title"].as_str().unwrap_or_default(), copyright),
+			None => image["title"].as_str().unwrap_or_default().to_string()
+		})
+		.collect::<Vec<_>>()
+		.join("\n");
+
+	let mut payload = serde_json::json!({
+		"content": message,
+		"text": message,
+		"images": images,
+		"files": files
+	});
+	if let Some(color) = color {
+		payload["color"] = color.into();
+	}
+
+	let result = options.plain_client.post(webhook)
+		.json(&payload)
+		.send()
+		.await
+		.and_then(|response| response.error_for_status());
+	match result {
+		Ok(_) => status!("Notified {}", webhook),
+		Err(error) => eprintln!("Warning: couldn't notify {}: {}", webhook, error)
+	}
+}
+
+/// Size images are shrunk to before averaging their pixels, which is plenty for an average
+const COLOR_SAMPLE_SIZE: u32 = 64;
+
+/// The average color of an encoded image, as a hex code like `#1a2b3c`
+fn average_color(bytes: &[u8]) -> image::ImageResult<String> {
+	let image = image::load_from_memory(bytes)?
+		.thumbnail(COLOR_SAMPLE_SIZE, COLOR_SAMPLE_SIZE)
+		.to_rgb8();
+
+	let mut sums = [0u64; 3];
+	for pixel in image.pixels() {
+		for (sum, channel) in sums.iter_mut().zip(pixel.0) {
+			*sum += u64::from(channel);
+		}
+	}
+	let count = u64::from(image.width() * image.height()).max(1);
+	Ok(hex_color(sums.map(|sum| (sum / count) as u8)))
+}
+
+/// An RGB color as a hex code like `#1a2b3c`
+fn hex_color([red, green, blue]: [u8; 3]) -> String {
+	format!("#{:02x}{:02x}{:02x}", red, green, blue)
+}
+
+/// The color of a hex code like [hex_color] makes, ex. `#1a2b3c`
+fn parse_hex_color(hex: &str) -> Option<[u8; 3]> {
+	let digits = hex.strip_prefix('#').filter(|digits| digits.len() == 6 && digits.is_ascii())?;
+	let channel = |index: usize| u8::from_str_radix(&digits[index..index + 2], 16).ok();
+	Some([channel(0)?, channel(2)?, channel(4)?])
+}
+
+/// Rounds of k-means that [palette] runs, which is enough for the colors to stop moving much
+const PALETTE_ITERATIONS: usize = 10;
+
+/// The `size` most common colors of an encoded image, found with k-means clustering, from the
+/// biggest cluster to the smallest.
+/// 
+/// The clusters start out evenly spread through the pixels from darkest to brightest, so the same
+/// image always gets the same palette. There can be fewer colors than `size` if the image doesn't
+/// have that many.
+fn palette(bytes: &[u8], size: usize) -> image::ImageResult<Vec<[u8; 3]>> {
+	let image = image::load_from_memory(bytes)?
+		.thumbnail(COLOR_SAMPLE_SIZE, COLOR_SAMPLE_SIZE)
+		.to_rgb8();
+	let mut pixels: Vec<[f64; 3]> = image.pixels().map(|pixel| pixel.0.map(f64::from)).collect();
+	if pixels.is_empty() {
+		return Ok(Vec::new())
+	}
+	pixels.sort_by(|a, b| a.iter().sum::<f64>().total_cmp(&b.iter().sum::<f64>()));
+
+	let size = size.min(pixels.len());
+	let mut centers: Vec<[f64; 3]> = (0..size).map(|index| pixels[(2 * index + 1) * pixels.len() / (2 * size)]).collect();
+	let mut counts = vec![0; size];
+	for _ in 0..PALETTE_ITERATIONS {
+		let mut sums = vec![[0.0; 3]; size];
+		counts = vec![0; size];
+		for pixel in &pixels {
+			let distance = |center: &[f64; 3]| center.iter().zip(pixel).map(|(a, b)| (a - b) * (a - b)).sum::<f64>();
+			let (nearest, _) = centers.iter()
+				.enumerate()
+				.min_by(|(_, a), (_, b)| distance(a).total_cmp(&distance(b)))
+				.unwrap();
+			for (sum, channel) in sums[nearest].iter_mut().zip(pixel) {
+				*sum += channel;
+			}
+			counts[nearest] += 1;
+		}
+		for ((center, sum), &count) in centers.iter_mut().zip(sums).zip(&counts) {
+			// Clusters that lost all of their pixels keep their center, and get dropped at the end
+			if count > 0 {
+				*center = sum.map(|channel| channel / f64::from(count));
+			}
+		}
+	}
+
+	let mut clusters: Vec<([f64; 3], u32)> = centers.into_iter().zip(counts).filter(|&(_, count)| count > 0).collect();
+	clusters.sort_by(|(_, a), (_, b)| b.cmp(a));
+	Ok(clusters.into_iter().map(|(center, _)| center.map(|channel| channel.round() as u8)).collect())
+}
+
+/// Render `colors` as a palette file for `file_path`: a GIMP palette if it ends in .gpl, or CSS
+/// variables otherwise
+fn render_palette(colors: &[[u8; 3]], file_path: &Path) -> String {
+	let mut rendered = String::new();
+	if file_path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("gpl")) {
+		rendered.push_str("GIMP Palette\nName: bingimage\nColumns: 0\n#\n");
+		for (index, [red, green, blue]) in colors.iter().enumerate() {
+			rendered.push_str(&format!("{:3} {:3} {:3}\tcolor-{}\n", red, green, blue, index + 1));
+		}
+	} else {
+		rendered.push_str(":root {\n");
+		for (index, &color) in colors.iter().enumerate() {
+			rendered.push_str(&format!("\t--color-{}: {};\n", index + 1, hex_color(color)));
+		}
+		rendered.push_str("}\n");
+	}
+	rendered
+}
+
+/// `file_name` with `variant` added before its extension, ex. `1920x1080.blur.jpg`
+fn variant_file_name(file_name: &str, variant: &str) -> String {
+	let path = Path::new(file_name);
+	let variant_path = match path.extension() {
+		Some(extension) => path.with_extension(format!("{}.{}", variant, extension.to_string_lossy())),
+		None => path.with_extension(variant)
+	};
+	variant_path.to_string_lossy().into_owned()
+}
+
+/// Run `job`, which decodes or encodes images, on a blocking thread, with at most --image-threads
+/// of them running at once.
+/// 
+/// Decoding and encoding a big image can take long enough to hold up the downloads if it's done
+/// on the async runtime's threads.
+async fn run_image_job<T, E>(config: &Config, job: impl FnOnce() -> Result<T, E> + Send + 'static) -> Result<T, String>
+	where T: Send + 'static, E: std::fmt::Display + Send + 'static {
+	let _permit = config.image_permits.acquire().await;
+	match tokio::task::spawn_blocking(job).await {
+		Ok(result) => result.map_err(|error| error.to_string()),
+		Err(error) => Err(error.to_string())
+	}
+}
+
+/// JPEG quality that images are encoded with after [apply_filters]
+const FILTERED_QUALITY: u8 = 90;
+
+/// Apply --grayscale, --blur, --brightness and --title-overlay to an encoded image, in that order,
+/// and encode it again as a JPEG. `title` is what --title-overlay writes.
+fn apply_filters(bytes: &[u8], title: &str, config: &Config) -> image::ImageResult<Vec<u8>> {
+	let mut image = image::load_from_memory(bytes)?;
+	if config.grayscale {
+		image = image.grayscale();
+	}
+	if let Some(sigma) = config.blur {
+		image = image.blur(sigma);
+	}
+	if let Some(delta) = config.brightness {
+		image = image.brighten(delta);
+	}
+	if let (true, Some(font)) = (config.title_overlay, &config.font) {
+		let mut rgb = image.into_rgb8();
+		// Checked when the config was read
+		let color = parse_hex_color(&config.overlay_color).unwrap();
+		draw_title(&mut rgb, title, font, config.overlay_size, color, &config.overlay_corner);
+		image = image::DynamicImage::ImageRgb8(rgb);
+	}
+
+	encode_jpeg(&image, config.progressive, config.chroma.as_deref())
+}
+
+/// Encode `image` as a JPEG of [FILTERED_QUALITY], which is `progressive` with --progressive.
+/// `chroma` is --chroma's subsampling, ex. `420`, or the encoder's default if it's `None`.
+fn encode_jpeg(image: &image::DynamicImage, progressive: bool, chroma: Option<&str>) -> image::ImageResult<Vec<u8>> {
+	let (width, height) = match (u16::try_from(image.width()), u16::try_from(image.height())) {
+		(Ok(width), Ok(height)) => (width, height),
+		// JPEGs can't be any bigger
+		_ => return Err(image::ImageError::Limits(image::error::LimitError::from_kind(image::error::LimitErrorKind::DimensionError)))
+	};
+	let mut encoded = Vec::new();
+	let mut encoder = jpeg_encoder::Encoder::new(&mut encoded, FILTERED_QUALITY);
+	encoder.set_progressive(progressive);
+	match chroma {
+		Some("420") => encoder.set_sampling_factor(jpeg_encoder::SamplingFactor::R_4_2_0),
+		Some("422") => encoder.set_sampling_factor(jpeg_encoder::SamplingFactor::R_4_2_2),
+		Some("444") => encoder.set_sampling_factor(jpeg_encoder::SamplingFactor::R_4_4_4),
+		_ => ()
+	}
+	let written = match image {
+		image::DynamicImage::ImageLuma8(luma) => encoder.encode(luma.as_raw(), width, height, jpeg_encoder::ColorType::Luma),
+		image => encoder.encode(image.to_rgb8().as_raw(), width, height, jpeg_encoder::ColorType::Rgb)
+	};
+	written.map_err(|error| image::ImageError::Encoding(image::error::EncodingError::new(image::ImageFormat::Jpeg.into(), error)))?;
+	Ok(encoded)
+}
+
+/// How dark the background behind --title-overlay's text is, from 0 to 1
+const OVERLAY_SHADE: f32 = 0.5;
+
+/// Write `title` onto `image` in one line, in `corner` (ex. `bottom-left`), `size` pixels tall.
+/// 
+/// The text is kept half its size away from the edges, and has a darkened box behind it. A title
+/// too long for the image is cut off at the edge.
+fn draw_title(image: &mut image::RgbImage, title: &str, font: &FontArc, size: f32, color: [u8; 3], corner: &str) {
+	let font = font.as_scaled(PxScale::from(size));
+	let mut glyphs = Vec::new();
+	let mut caret = 0.0;
+	let mut previous = None;
+	for character in title.chars() {
+		let glyph = font.scaled_glyph(character);
+		if let Some(previous) = previous {
+			caret += font.kern(previous, glyph.id);
+		}
+		previous = Some(glyph.id);
+		let advance = font.h_advance(glyph.id);
+		glyphs.push((glyph, caret));
+		caret += advance;
+	}
+
+	let (text_width, text_height) = (caret, font.height());
+	let margin = size / 2.0;
+	let padding = size / 4.0;
+	let (width, height) = (image.width() as f32, image.height() as f32);
+	let x = match corner.ends_with("right") {
+		true => width - margin - text_width,
+		false => margin
+	};
+	let y = match corner.starts_with("bottom") {
+		true => height - margin - text_height,
+		false => margin
+	};
+
+	let blend = |pixel: &mut image::Rgb<u8>, target: [u8; 3], amount: f32| {
+		for (channel, target) in pixel.0.iter_mut().zip(target) {
+			*channel = (f32::from(*channel) * (1.0 - amount) + f32::from(target) * amount).round() as u8;
+		}
+	};
+	let left = (x - padding).max(0.0) as u32;
+	let top = (y - padding).max(0.0) as u32;
+	let right = ((x + text_width + padding).max(0.0) as u32).min(image.width());
+	let bottom = ((y + text_height + padding).max(0.0) as u32).min(image.height());
+	for pixel_y in top..bottom {
+		for pixel_x in left..right {
+			blend(image.get_pixel_mut(pixel_x, pixel_y), [0, 0, 0], OVERLAY_SHADE);
+		}
+	}
+
+	for (mut glyph, offset) in glyphs {
+		glyph.position = ab_glyph::point(x + offset, y + font.ascent());
+		let outline = match font.outline_glyph(glyph) {
+			Some(outline) => outline,
+			// Spaces and such have nothing to draw
+			None => continue
+		};
+		let bounds = outline.px_bounds();
+		outline.draw(|glyph_x, glyph_y, coverage| {
+			let pixel_x = bounds.min.x as i64 + i64::from(glyph_x);
+			let pixel_y = bounds.min.y as i64 + i64::from(glyph_y);
+			if (0..i64::from(image.width())).contains(&pixel_x) && (0..i64::from(image.height())).contains(&pixel_y) {
+				blend(image.get_pixel_mut(pixel_x as u32, pixel_y as u32), color, coverage.min(1.0));
+			}
+		});
+	}
+}
+
+/// Print a table of the size of each downloaded file, from fewest to most pixels
+fn print_comparison(downloaded: &[Downloaded]) {
+	let mut downloaded: Vec<&Downloaded> = downloaded.iter().collect();
+	downloaded.sort_by_key(|file| file.resolution.pixels());
+
+	status!("{:<12}{:>12}{:>14}{:>16}", "Resolution", "Pixels", "Size (bytes)", "Bytes per pixel");
+	for file in downloaded {
+		let pixels = file.resolution.pixels();
+		let per_pixel = file.size as f64 / pixels.max(1) as f64;
+		status!("{:<12}{:>12}{:>14}{:>16.3}", file.resolution.to_string(), pixels, file.size, per_pixel);
+	}
+}
+
+/// A report of a run for --summary-json-to: which files were written and which weren't, how long
+/// it all took and how big it was, and which version of bingimage it was
+fn run_summary(downloaded: &[Downloaded], failed: &[Failed], started: chrono::DateTime<Local>, elapsed: Duration) -> JsonValue {
+	serde_json::json!({
+		"version": env!("CARGO_PKG_VERSION"),
+		"started": started.to_rfc3339(),
+		"duration": elapsed.as_secs_f64(),
+		"bytes": downloaded.iter().map(|file| file.size).sum::<u64>(),
+		"downloaded": downloaded.iter().map(|file| serde_json::json!({
+			"file": file.file_name,
+			"resolution": file.resolution.to_string(),
+			"size": file.size,
+			"duration": file.duration.as_secs_f64()
+		})).collect::<Vec<_>>(),
+		"failed": failed.iter().map(|file| serde_json::json!({
+			"file": file.file_name,
+			"reason": file.reason
+		})).collect::<Vec<_>>()
+	})
+}
+
+/// Print how long each download took, and the total size, time and throughput of the whole run
+fn print_metrics(downloaded: &[Downloaded], elapsed: Duration) {
+	// Already-written files can take no measurable time at all
+	let per_second = |bytes: u64, duration: Duration| bytes as f64 / duration.as_secs_f64().max(f64::EPSILON);
+
+	status!("{:<32}{:>14}{:>10}{:>18}", "File", "Size (bytes)", "Time (s)", "Bytes per second");
+	for file in downloaded {
+		status!("{:<32}{:>14}{:>10.3}{:>18.0}", file.file_name, file.size, file.duration.as_secs_f64(), per_second(file.size, file.duration));
+	}
+	let total = downloaded.iter().map(|file| file.size).sum();
+	status!("{} bytes in {:.3}s, {:.0} bytes per second", total, elapsed.as_secs_f64(), per_second(total, elapsed));
+}
+
+/// Contents of README.md for an image with `title` and, if there is one, `copyright`
+fn render_metadata(title: &str, copyright: Option<&str>) -> String {
+	let mut metadata_md = format!("{}\n# {}\n", METADATA_MARKER, title);
+	if let Some(copyright) = copyright {
+		metadata_md += &format!("## {}\n", copyright);
+	}
+	metadata_md
+}
+
+/// Write a markdown file with properties from the [ImageProperties] to a specified [Output] as `file_name`.
+/// 
+/// An existing README.md without [METADATA_MARKER] is left alone unless --force is set, and one
+/// that already has the same contents isn't rewritten, so its modification time doesn't change.
+/// 
+/// Doesn't make any http connections. Doesn't return an error, but will print any errors it gets to stderr. 
+async fn create_metadata(properties: ImageProperties, output: Arc<Output>, file_name: String, config: Arc<Config>) {
+	let metadata_md = render_metadata(&properties.title, properties.copyright.as_deref().map(String::as_str));
+	let paths = match &*output {
+		Output::Archive(archive) => {
+			archive.add(&file_name, metadata_md.as_bytes(), CompressionMethod::Deflated);
+			return
+		},
+		Output::Directories(paths) => paths
+	};
+
+	for path in paths {
+		let file_path = path.join(&file_name);
+		let existing = std::fs::read_to_string(&file_path);
+		if existing.as_ref().is_ok_and(|existing| *existing == metadata_md) {
+			status!("Metadata unchanged in {:?}", file_path);
+			continue
+		}
+		if !config.force {
+			let foreign = match existing {
+				Ok(existing) => !existing.contains(METADATA_MARKER),
+				Err(error) => error.kind() != std::io::ErrorKind::NotFound
+			};
+			if foreign {
+				eprintln!("Not overwriting file {:?}: it wasn't written by bingimage. Use --force to overwrite it anyway", file_path);
+				continue
+			}
+		}
+
+		let written = write_atomically(&file_path, config.fsync, config.temp_dir.as_deref(), metadata_md.as_bytes());
+		match written {
+			Ok(_) => status!("Successfully written file {:?}", file_path),
+			Err(error) => eprintln!("Error writing file {:?}: {}", file_path, error)
+		}
+	}
+}
+
+/// Write a plain text file with the title and copyright from the [ImageProperties] to a specified
+/// [Output] as `file_name`, for a single image.
+/// 
+/// Doesn't make any http connections. Doesn't return an error, but will print any errors it gets to stderr.
+async fn create_sidecar(properties: ImageProperties, output: Arc<Output>, file_name: String, config: Arc<Config>) {
+	let mut sidecar = format!("{}\n", properties.title);
+	if let Some(copyright) = &properties.copyright {
+		sidecar += &format!("{}\n", copyright);
+	}
+	let paths = match &*output {
+		Output::Archive(archive) => {
+			archive.add(&file_name, sidecar.as_bytes(), CompressionMethod::Deflated);
+			return
+		},
+		Output::Directories(paths) => paths
+	};
+
+	for path in paths {
+		let file_path = path.join(&file_name);
+		match write_atomically(&file_path, config.fsync, config.temp_dir.as_deref(), sidecar.as_bytes()) {
+			Ok(_) => status!("Successfully written file {:?}", file_path),
+			Err(error) => eprintln!("Error writing file {:?}: {}", file_path, error)
+		}
+	}
+}
+
+/// Write the properties from the [ImageProperties] as JSON to `target`, or to stdout if it's `-`.
+/// 
+/// Doesn't make any http connections. Doesn't return an error, but will print any errors it gets to stderr.
+async fn create_json(properties: ImageProperties, target: String, config: Arc<Config>) {
+	let mut metadata = (*properties.extra).clone();
+	metadata.insert("title".to_string(), properties.title.as_str().into());
+	if let Some(copyright) = &properties.copyright {
+		metadata.insert("copyright".to_string(), copyright.as_str().into());
+	}
+	metadata.insert("url".to_string(), absolute_url(&properties.url).into());
+	metadata.insert("date".to_string(), properties.date.as_str().into());
+	let metadata = JsonValue::Object(metadata);
+
+	if target == "-" {
+		println!("{}", metadata);
+		return
+	}
+
+	let rendered = if config.compact_json {
+		serde_json::to_string(&metadata)
+	} else {
+		serde_json::to_string_pretty(&metadata)
+	};
+	// Serializing a JsonValue can't fail
+	let rendered = rendered.unwrap() + "\n";
+
+	match write_atomically(std::path::Path::new(&target), config.fsync, config.temp_dir.as_deref(), rendered.as_bytes()) {
+		Ok(_) => status!("Successfully written file {:?}", target),
+		Err(error) => eprintln!("Error writing file {:?}: {}", target, error)
+	}
+}
 
-	let mut resolutions: Vec<Arc<Resolution>> = Vec::new();
+/// A file that [download] wrote
+struct Downloaded {
+	pub resolution: Arc<Resolution>,
+	/// Name of the file, relative to the [Output]
+	pub file_name: String,
+	/// Size of the file in bytes
+	pub size: u64,
+	/// How long it took to download and write
+	pub duration: Duration
+}
+
+/// A file that [download] didn't write
+struct Failed {
+	/// Name of the file, relative to the [Output]
+	pub file_name: String,
+	/// Why it wasn't written
+	pub reason: String
+}
+
+/// Every setting, read from the arguments (and environment variables) once by
+/// [Config::from_matches], so nothing past that has to look at the matches or validate them.
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Config {
+	/// Resolutions to download, after --max-dimension and --auto-correct, without duplicates
+	pub resolutions: Vec<Resolution>,
+	pub readme: bool,
+	pub only_metadata: bool,
+	pub copyright_sidecar: bool,
+	/// Directories to write to, empty when writing an archive or not downloading anything
+	pub paths: Vec<PathBuf>,
+	pub archive: Option<PathBuf>,
+	pub output_template: String,
+	pub normalize_extension: bool,
+	pub create_dirs: bool,
+	pub hardlink_duplicates: bool,
+	pub date_format: String,
+	pub json_out: Option<String>,
+	pub strip_query: bool,
+	pub compact_json: bool,
+	pub save_response: Option<PathBuf>,
+	pub pretty_response: bool,
+	pub source: String,
+	/// Markets to get images for, empty for Bing's default one
+	pub markets: Vec<String>,
+	pub title_fallback: bool,
+	pub jobs: usize,
+	pub image_threads: usize,
+	/// Permits for [run_image_job], one per [Config::image_threads]
+	#[serde(skip)]
+	pub image_permits: Semaphore,
+	pub download_order: String,
+	pub exit_on_first_success: bool,
+	pub fail_fast: bool,
+	pub skip_metadata_on_download_failure: bool,
+	/// Bytes per second
+	pub ratelimit_global: Option<u64>,
+	pub parallel_markets_limit: usize,
+	pub fsync: bool,
+	pub temp_dir: Option<PathBuf>,
+	pub force: bool,
+	/// Seconds
+	pub startup_grace: u64,
+	pub retries: u32,
+	pub retry_budget: Option<u32>,
+	pub retry_on_partial: bool,
+	pub head_check: bool,
+	/// Seconds
+	pub connect_timeout: Option<u64>,
+	pub ignore_tls_errors: bool,
+	/// Seconds
+	pub read_timeout: Option<u64>,
+	#[serde(serialize_with = "serialize_hidden")]
+	pub auth_header: Option<HeaderValue>,
+	pub cache_dir: Option<PathBuf>,
+	pub only_new_image: bool,
+	pub webhook: Option<String>,
+	pub grayscale: bool,
+	pub blur: Option<f32>,
+	pub brightness: Option<i32>,
+	pub title_overlay: bool,
+	pub overlay_font: Option<PathBuf>,
+	/// Loaded from [Config::overlay_font]
+	#[serde(skip)]
+	pub font: Option<FontArc>,
+	pub overlay_corner: String,
+	/// Pixels
+	pub overlay_size: f32,
+	pub overlay_color: String,
+	pub progressive: bool,
+	pub chroma: Option<String>,
+	pub keep_original: bool,
+	pub extract_color: bool,
+	pub color_sidecar: bool,
+	pub palette_file: Option<PathBuf>,
+	pub palette_size: usize,
+	pub metrics: bool,
+	pub summary_json_to: Option<PathBuf>,
+	pub compare_resolutions: bool,
+	pub ephemeral: bool,
+	pub check: bool,
+	pub redownload_corrupt: bool,
+	pub probe_supported_resolutions: bool,
+	pub fail_if_empty_archive: bool,
+	pub watch: bool,
+	/// Seconds
+	pub interval: u64,
+	pub at: Option<NaiveTime>,
+	pub lockfile: Option<PathBuf>,
+	pub validate_schema: bool,
+	pub strict_json: bool,
+	pub verify_checksum_against: Option<PathBuf>,
+	pub trace: bool,
+	pub url_strategy: String,
+	pub url_template: String,
+	pub replace_token: String,
+	pub repeat: u32
+}
+
+impl Config {
+	/// Whether any of the filters that [apply_filters] applies are on
+	pub fn has_filters(&self) -> bool {
+		self.grayscale || self.blur.is_some() || self.brightness.is_some() || self.title_overlay
+	}
 
-	match app.values_of("resolution") {
-		Some(values) => {
-			for value in values {
-				let split: Vec<&str> = value.split('x').collect();
-				if split.len() != 2 {
-					Error::exit(&res_error)
+	/// The filters that are on joined with dashes, ex. `grayscale-blur`, for naming filtered
+	/// variants
+	pub fn filter_name(&self) -> String {
+		let filters = [
+			("grayscale", self.grayscale),
+			("blur", self.blur.is_some()),
+			("brightness", self.brightness.is_some()),
+			("title", self.title_overlay)
+		];
+		filters.iter()
+			.filter(|(_, on)| *on)
+			.map(|(name, _)| *name)
+			.collect::<Vec<_>>()
+			.join("-")
+	}
+
+	/// Read every setting from `matches`, exiting with one of `command`'s errors if any of them
+	/// are invalid. Warnings about resolutions Bing doesn't serve are printed here too.
+	pub fn from_matches(command: &mut Command, matches: &ArgMatches) -> Self {
+		// Only missing when no images are being downloaded. Each occurrence can also be a comma-separated list
+		let mut values: Vec<String> = values_of_list(matches, "resolution").into_iter()
+			.flat_map(|value| value.split(','))
+			.map(|value| value.trim().to_string())
+			.collect();
+		if let Some(variable) = matches.value_of("resolution-from-env") {
+			match std::env::var(variable) {
+				Ok(value) => values.push(value.trim().to_string()),
+				Err(_) => command.error(ErrorKind::InvalidValue, format!("The {} environment variable from --resolution-from-env isn't set.", variable)).exit()
+			}
+		}
+		let mut resolutions = Vec::new();
+		for value in values {
+			let mut resolution = value.parse::<Resolution>().unwrap_or_else(|_| {
+				command.error(ErrorKind::InvalidValue, "Can't parse resolution value. See help for information on how to format it.").exit()
+			});
+			if matches.is_present("max-dimension") {
+				let capped = resolution.capped();
+				if capped != resolution {
+					eprintln!("{} is bigger than Bing serves; capping it at {}", resolution, capped);
+					resolution = capped;
 				}
+			}
+			if !resolution.is_supported() {
+				let nearest = match (matches.is_present("prefer-portrait-for-phones"), resolution.is_phone_like(), resolution.is_landscape()) {
+					(true, true, _) => resolution.nearest_supported_where(|supported| !supported.is_landscape()),
+					(true, _, true) => resolution.nearest_supported_where(Resolution::is_landscape),
+					_ => resolution.nearest_supported()
+				};
+				if matches.is_present("auto-correct") {
+					eprintln!("{} not supported; downloading nearest {} instead", resolution, nearest);
+					resolution = nearest;
+				} else {
+					eprintln!("{} not supported; nearest is {}", resolution, nearest);
+				}
+			}
+			// Two downloads of the same resolution would write the same file at once
+			if resolutions.contains(&resolution) {
+				eprintln!("{} is already being downloaded; skipping the duplicate", resolution);
+				continue
+			}
+			resolutions.push(resolution)
+		}
 
-				match (split[0].parse::<u16>(), split[1].parse::<u16>()) {
-					(Err(_), _) => Error::exit(&res_error),
-					(_, Err(_)) => Error::exit(&res_error),
-					(x, y) => {
-						resolutions.push(Arc::new(Resolution::new(x.unwrap(), y.unwrap())))
-					}
+		let font = matches.value_of("overlay-font").filter(|_| matches.is_present("title-overlay")).map(|font_path| {
+			match std::fs::read(font_path).map_err(|error| error.to_string()).and_then(|font| FontArc::try_from_vec(font).map_err(|error| error.to_string())) {
+				Ok(font) => font,
+				Err(error) => command.error(ErrorKind::InvalidValue, format!("Can't load font {:?}: {}", font_path, error)).exit()
+			}
+		});
+		let overlay_size: f32 = matches.value_of_t_or_exit("overlay-size");
+		if overlay_size <= 0.0 || !overlay_size.is_finite() {
+			command.error(ErrorKind::InvalidValue, "Overlay size must be a positive number of pixels.").exit()
+		}
+		let overlay_color = matches.value_of("overlay-color").unwrap().to_lowercase();
+		if parse_hex_color(&overlay_color).is_none() {
+			command.error(ErrorKind::InvalidValue, "Overlay color must be a hex code, ex. #ffcc00.").exit()
+		}
+
+		let has_filters = ["grayscale", "blur", "brightness", "title-overlay"].into_iter().any(|filter| matches.is_present(filter));
+		for option in ["progressive", "chroma"].into_iter().filter(|option| matches.is_present(option) && !has_filters) {
+			eprintln!("Warning: --{} only changes images that are filtered, and there are no filters, so it does nothing", option);
+		}
+
+		let image_threads = optional_value(matches, "image-threads")
+			.unwrap_or_else(|| std::thread::available_parallelism().map_or(1, usize::from));
+
+		let url_template = matches.value_of("url-template").unwrap().to_string();
+		if !is_valid_url_template(&url_template) {
+			command.error(ErrorKind::InvalidValue, "URL template can only contain the {base}, {w}, {h} and {res} placeholders.").exit()
+		}
+
+		let output_template = matches.value_of("output-template").unwrap().to_string();
+		if !is_valid_template(&output_template) {
+			command.error(ErrorKind::InvalidValue, "Output template can only contain the {resolution}, {date}, {year}, {month}, {day} and {urlhash} placeholders, and has to be a relative path without .. or backslashes.").exit()
+		}
+		let date_format = matches.value_of("date-format").unwrap().to_string();
+		if !is_valid_date_format(&date_format) {
+			command.error(ErrorKind::InvalidValue, "Date format must be a valid strftime format that can be a file name: not empty, . or .., and without path separators.").exit()
+		}
+
+		let temp_dir: Option<PathBuf> = optional_value(matches, "temp-dir");
+		if temp_dir.as_ref().is_some_and(|temp_dir| !temp_dir.is_dir()) {
+			command.error(ErrorKind::InvalidValue, "--temp-dir must be a directory.").exit()
+		}
+
+		let archive = optional_value(matches, "archive");
+		let paths = matches.values_of_t::<PathBuf>("path").unwrap_or_default();
+		// Enforce that the paths are directories
+		if !paths.iter().all(|path| path.is_dir()) {
+			command.error(ErrorKind::InvalidValue, "Output paths must be directories.").exit()
+		}
+		for path in &paths {
+			if path.symlink_metadata().is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+				let target = path.canonicalize().unwrap_or_else(|_| path.clone());
+				eprintln!("Output path {:?} is a symlink to {:?}", path, target);
+				if matches.is_present("no-follow") {
+					command.error(ErrorKind::InvalidValue, "Output paths can't be symlinks with --no-follow.").exit()
 				}
 			}
-		},
-		// Unreachable? This argument is marked as required
-		None => Error::exit(&res_error)
-	}
+		}
+
+		let markets: Vec<String> = values_of_list(matches, "market").into_iter().map(str::to_string).collect();
+		// Markets name subdirectories and go into the API's query string as they are
+		if let Some(market) = markets.iter().find(|market| !is_valid_market(market)) {
+			command.error(ErrorKind::InvalidValue, format!("Invalid market {:?}: markets are a language and a region code, ex. en-US.", market)).exit()
+		}
+		if markets.len() > 1 && matches.is_present("json-out") {
+			command.error(ErrorKind::ArgumentConflict, "--json-out can only be used with a single market.").exit()
+		}
+		if markets.len() > 1 && matches.is_present("save-response") {
+			command.error(ErrorKind::ArgumentConflict, "--save-response can only be used with a single market.").exit()
+		}
+		if matches.is_present("redownload-corrupt") && matches.value_of("source") != Some("bing") {
+			command.error(ErrorKind::ArgumentConflict, "--redownload-corrupt only works with Bing's API, since it's the only source with past images.").exit()
+		}
+		if matches.is_present("probe-supported-resolutions") && matches.value_of("source") != Some("bing") {
+			command.error(ErrorKind::ArgumentConflict, "--probe-supported-resolutions only works with Bing's API, since the other sources only have one resolution.").exit()
+		}
 
-	// Get the path from the arguments
-	let path = Arc::new(app.value_of_t_or_exit::<PathBuf>("path"));
-	// Enforce that the path is a directory
-	if !path.is_dir() {
-		Error::exit(&path_error)
+		let auth_header = matches.value_of("auth-header").map(|auth| {
+			let mut auth = HeaderValue::from_str(auth).unwrap_or_else(|_| {
+				command.error(ErrorKind::InvalidValue, "Authorization header value can only contain visible ASCII characters.").exit()
+			});
+			auth.set_sensitive(true);
+			auth
+		});
+
+		Config {
+			resolutions,
+			readme: matches.is_present("readme"),
+			only_metadata: matches.is_present("only-metadata"),
+			copyright_sidecar: matches.is_present("copyright-sidecar"),
+			paths,
+			archive,
+			output_template,
+			normalize_extension: matches.is_present("normalize-extension"),
+			create_dirs: matches.is_present("create-dirs"),
+			hardlink_duplicates: matches.is_present("hardlink-duplicates"),
+			date_format,
+			json_out: matches.value_of("json-out").map(str::to_string),
+			strip_query: matches.is_present("strip-query"),
+			compact_json: matches.is_present("compact-json"),
+			save_response: optional_value(matches, "save-response"),
+			pretty_response: matches.is_present("pretty-response"),
+			source: matches.value_of("source").unwrap().to_string(),
+			markets,
+			title_fallback: matches.is_present("title-fallback"),
+			jobs: matches.value_of_t_or_exit("jobs"),
+			image_threads,
+			image_permits: Semaphore::new(image_threads),
+			download_order: matches.value_of("download-order").unwrap().to_string(),
+			exit_on_first_success: matches.is_present("exit-on-first-success"),
+			fail_fast: matches.is_present("fail-fast"),
+			skip_metadata_on_download_failure: matches.is_present("skip-metadata-on-download-failure"),
+			ratelimit_global: optional_value(matches, "ratelimit-global"),
+			parallel_markets_limit: matches.value_of_t_or_exit("parallel-markets-limit"),
+			fsync: !matches.is_present("no-fsync"),
+			temp_dir,
+			force: matches.is_present("force"),
+			startup_grace: matches.value_of_t_or_exit("startup-grace"),
+			retries: matches.value_of_t_or_exit("retries"),
+			retry_budget: optional_value(matches, "retry-budget"),
+			retry_on_partial: matches.is_present("retry-on-partial"),
+			head_check: matches.is_present("head-check"),
+			connect_timeout: optional_value(matches, "connect-timeout"),
+			ignore_tls_errors: matches.is_present("ignore-tls-errors"),
+			read_timeout: optional_value(matches, "read-timeout"),
+			auth_header,
+			cache_dir: optional_value(matches, "cache-dir"),
+			only_new_image: matches.is_present("only-new-image"),
+			webhook: matches.value_of("webhook").map(str::to_string),
+			grayscale: matches.is_present("grayscale"),
+			blur: optional_value(matches, "blur"),
+			brightness: optional_value(matches, "brightness"),
+			title_overlay: matches.is_present("title-overlay"),
+			overlay_font: optional_value(matches, "overlay-font"),
+			font,
+			overlay_corner: matches.value_of("overlay-corner").unwrap().to_string(),
+			overlay_size,
+			overlay_color,
+			progressive: matches.is_present("progressive"),
+			chroma: optional_value(matches, "chroma"),
+			keep_original: matches.is_present("keep-original"),
+			extract_color: matches.is_present("extract-color"),
+			color_sidecar: matches.is_present("color-sidecar"),
+			palette_file: optional_value(matches, "palette-file"),
+			palette_size: matches.value_of_t_or_exit("palette-size"),
+			metrics: matches.is_present("metrics"),
+			summary_json_to: optional_value(matches, "summary-json-to"),
+			compare_resolutions: matches.is_present("compare-resolutions"),
+			ephemeral: matches.is_present("ephemeral"),
+			check: matches.is_present("check"),
+			redownload_corrupt: matches.is_present("redownload-corrupt"),
+			probe_supported_resolutions: matches.is_present("probe-supported-resolutions"),
+			fail_if_empty_archive: matches.is_present("fail-if-empty-archive"),
+			watch: matches.is_present("watch"),
+			interval: matches.value_of_t_or_exit("interval"),
+			// Checked by the validator
+			at: matches.value_of("at").map(|at| NaiveTime::parse_from_str(at, "%H:%M").unwrap()),
+			lockfile: optional_value(matches, "lockfile"),
+			validate_schema: matches.is_present("validate-schema"),
+			strict_json: matches.is_present("strict-json"),
+			verify_checksum_against: optional_value(matches, "verify-checksum-against"),
+			trace: matches.is_present("trace"),
+			url_strategy: matches.value_of("url-strategy").unwrap().to_string(),
+			url_template,
+			replace_token: matches.value_of("replace-token").unwrap().to_string(),
+			repeat: matches.value_of_t_or_exit("repeat")
+		}
 	}
+}
 
-	let json = reqwest::get("https://www.bing.com/HPImageArchive.aspx?format=js&idx=0&n=1")
-		.await?
-		.json::<JsonValue>()
-		.await?;
+/// Like [ArgMatches::value_of_t_or_exit], but `None` instead of exiting if the argument is missing
+fn optional_value<T: std::str::FromStr>(matches: &ArgMatches, id: &str) -> Option<T> where T::Err: std::fmt::Display {
+	matches.is_present(id).then(|| matches.value_of_t_or_exit(id))
+}
 
-	let meta = &json["images"][0];
+/// Every value of `id`. A value from its environment variable is split on commas and whitespace,
+/// since the variable can't be repeated like the option can, ex. `BINGIMAGE_RESOLUTION="1920x1080 1366x768"`
+fn values_of_list<'a>(matches: &'a ArgMatches, id: &str) -> Vec<&'a str> {
+	let values = matches.values_of(id).into_iter().flatten();
+	// clap doesn't count a value from the environment as an occurrence
+	match matches.occurrences_of(id) {
+		0 => values.flat_map(|value| value.split(|c: char| c == ',' || c.is_whitespace())).filter(|value| !value.is_empty()).collect(),
+		_ => values.collect()
+	}
+}
 
-	let url = Arc::new(meta["url"].to_string().trim_matches('"').to_string());
-	let title = Arc::new(meta["title"].to_string().trim_matches('"').to_string());
-	let copyright = Arc::new(meta["copyright"].to_string().trim_matches('"').to_string());
+/// Serialize a secret as "(hidden)", or null if it isn't set
+fn serialize_hidden<T, S: Serializer>(value: &Option<T>, serializer: S) -> Result<S::Ok, S::Error> {
+	match value {
+		Some(_) => serializer.serialize_str("(hidden)"),
+		None => serializer.serialize_none()
+	}
+}
 
-	let mut handles = Vec::new();
+/// Settings shared by every HTTP request
+#[derive(Clone)]
+struct RequestOptions {
+	/// Client that every request is sent with, so connections get reused
+	pub client: reqwest::Client,
+	/// Client without --auth-header, for requests that don't go to Bing like --webhook
+	pub plain_client: reqwest::Client,
+	/// Validators from earlier runs, if caching is enabled
+	pub cache: Option<Arc<Cache>>,
+	/// How long to keep retrying requests while the network is unreachable
+	pub grace: Duration,
+	/// Limit on how fast every response together can be read, if there is one
+	pub rate_limit: Option<Arc<RateLimiter>>,
+	/// How many times to retry each failed request
+	pub retries: u32,
+	/// Retries left for every request together, if they're limited
+	pub retry_budget: Option<Arc<AtomicU32>>,
+	/// How long a response can go without sending data before it's given up on
+	pub read_timeout: Option<Duration>,
+	/// Whether to send a HEAD request for each image before downloading it, see [head_check]
+	pub head_check: bool,
+	/// Where to write the metadata response to as-is, if anywhere
+	pub save_response: Option<PathBuf>,
+	/// Whether the saved response is indented
+	pub pretty_response: bool,
+	/// Whether metadata responses can't have anything after the JSON, see [check_strict_json]
+	pub strict_json: bool,
+	/// Whether to download images again if they're cut off, see [read_image]
+	pub retry_on_partial: bool,
+	/// Whether to log requests and responses to stderr
+	pub trace: bool
+}
 
-	for resolution in resolutions {
-		let properties = ImageProperties {
-			resolution: resolution,
-			url: url.clone(),
-			title: title.clone(),
-			copyright: copyright.clone()
-		};
-		handles.push(tokio::spawn(download(properties, path.clone())))
+impl RequestOptions {
+	/// Whether there's any of the retry budget left, using up one retry if there is
+	pub fn take_retry(&self) -> bool {
+		match &self.retry_budget {
+			Some(budget) => budget.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| left.checked_sub(1)).is_ok(),
+			None => true
+		}
 	}
-	if app.is_present("readme") {
-		let properties = ImageProperties {
-			resolution: Arc::new(Resolution::new(0, 0)),
-			url: url.clone(),
-			title: title.clone(),
-			copyright: copyright.clone()
-		};
-		handles.push(tokio::spawn(create_metadata(properties, path.clone())))
+}
+
+/// A token bucket shared by every request, so that together they read at most a number of bytes per
+/// second.
+struct RateLimiter {
+	/// Bytes per second
+	rate: f64,
+	/// Bytes that can be read right away, and when that was last worked out
+	state: Mutex<(f64, Instant)>
+}
+
+impl RateLimiter {
+	pub fn new(bytes_per_second: u64) -> Self {
+		let rate = bytes_per_second as f64;
+		RateLimiter { rate, state: Mutex::new((rate, Instant::now())) }
 	}
 
-	for handle in handles {
-		tokio::try_join!(handle)?;
+	/// Account for `bytes` that were just read, waiting until they fit under the limit
+	pub async fn take(&self, bytes: usize) {
+		let wait = {
+			let mut state = self.state.lock().unwrap();
+			let now = Instant::now();
+			// Refill for the time since the last take, but never store more than a second's worth
+			state.0 = (state.0 + now.duration_since(state.1).as_secs_f64() * self.rate).min(self.rate);
+			state.1 = now;
+			// The bucket can go negative, which makes every task wait until it's paid back
+			state.0 -= bytes as f64;
+			(-state.0 / self.rate).max(0.0)
+		};
+		if wait > 0.0 {
+			tokio::time::sleep(Duration::from_secs_f64(wait)).await;
+		}
 	}
-	Ok(())
 }
 
-/// Download an image with [ImageProperties] to a specified path.
+/// Validators (ETag and Last-Modified) of earlier responses, kept in between runs so requests can
+/// be made conditional.
 /// 
-/// Doesn't return an error, but will print any errors it gets to stderr.
-async fn download(properties: ImageProperties, path: Arc<PathBuf>) {
-	let res_string = properties.resolution.to_string();
-	let file_name = format!("{}.jpg", res_string);
-	let file_path = path.join(&file_name);
-	
-	// Replace the resolution in the image path with our own
-	let url = properties.url.replace("1920x1080", &res_string);
-	// Add the base URL
-	let url = format!("https://bing.com{}", url);
-	let image = reqwest::get(&url).await;
+/// Entries are keyed by URL, and hold whatever is needed to reuse the response when the server says
+/// it's not modified: the parsed body for metadata, and the path the file was written to for images.
+/// The [LAST_SEEN_KEY] entry isn't a URL, and holds the `hsh` of each market's image instead.
+struct Cache {
+	path: PathBuf,
+	entries: Mutex<serde_json::Map<String, JsonValue>>
+}
 
-	match image {
-		Ok(response) => {
-			match response.bytes().await {
-				Ok(bytes) => {
-					match File::create(&file_path) {
-						Ok(mut file) => {
-							match file.write(&bytes) {
-								Ok(len) => {
-									if len > bytes.len() {
-										eprintln!("Error writing file {:?}: entire file may not have been written", file_path);
-										return
-									}
-								},
-								Err(error) => {
-									eprintln!("Error writing file {:?}: {}", file_path, error);
-									return
-								}
-							};
+impl Cache {
+	/// Load the cache from `dir`, starting empty if there's nothing there yet
+	pub fn load(dir: &std::path::Path) -> std::io::Result<Self> {
+		std::fs::create_dir_all(dir)?;
+		let path = dir.join("validators.json");
 
-							match file.sync_all() {
-								Ok(_) => {
-									println!("Successfully written file {:?}", file_path);
-									return
-								},
-								Err(error) => {
-									eprintln!("Error writing file {:?}: {}", file_path, error);
-									return
-								}
-							};
-						},
-						Err(error) => {
-							eprintln!("Error creating file {:?}: {}", file_path, error);
-							return
-						}
-					}
-				},
-				Err(error) => {
-					eprintln!("Error downloading from \"{}\": {}", url, error);
-					return
+		let entries = match std::fs::read(&path) {
+			Ok(bytes) => match serde_json::from_slice(&bytes) {
+				Ok(JsonValue::Object(entries)) => entries,
+				_ => {
+					eprintln!("Ignoring unreadable cache file {:?}", path);
+					serde_json::Map::new()
 				}
+			},
+			Err(error) if error.kind() == std::io::ErrorKind::NotFound => serde_json::Map::new(),
+			Err(error) => return Err(error)
+		};
+		Ok(Self { path, entries: Mutex::new(entries) })
+	}
+
+	/// The entry stored for `url`, if any
+	pub fn get(&self, url: &str) -> Option<JsonValue> {
+		self.entries.lock().unwrap().get(url).cloned()
+	}
+
+	/// Remember the validators from a response to `url`, with `value` stored under `key` for
+	/// reusing later. Responses without any validators aren't worth remembering.
+	pub fn store(&self, url: &str, headers: &HeaderMap, key: &str, value: &JsonValue) {
+		let mut entry = serde_json::Map::new();
+		for name in [header::ETAG, header::LAST_MODIFIED] {
+			if let Some(validator) = headers.get(&name).and_then(|value| value.to_str().ok()) {
+				entry.insert(name.to_string(), validator.into());
 			}
-		},
-		Err(error) => {
-			eprintln!("Error downloading from \"{}\": {}", url, error);
+		}
+		if entry.is_empty() {
 			return
 		}
+		entry.insert(key.to_string(), value.clone());
+		self.entries.lock().unwrap().insert(url.to_string(), JsonValue::Object(entry));
+	}
+
+	/// The `hsh` of the image `market` had on the last successful run, see --only-new-image. The
+	/// default market is an empty string.
+	pub fn last_seen(&self, market: &str) -> Option<String> {
+		self.entries.lock().unwrap()
+			.get(LAST_SEEN_KEY)
+			.and_then(|hashes| hashes[market].as_str())
+			.map(str::to_string)
+	}
+
+	/// Remember that `market`'s image had the `hsh` of `hash`
+	pub fn set_last_seen(&self, market: &str, hash: &str) {
+		let mut entries = self.entries.lock().unwrap();
+		let hashes = entries.entry(LAST_SEEN_KEY).or_insert_with(|| JsonValue::Object(serde_json::Map::new()));
+		if let JsonValue::Object(hashes) = hashes {
+			hashes.insert(market.to_string(), hash.into());
+		}
+	}
+
+	/// Write the cache back to disk
+	pub fn save(&self) -> std::io::Result<()> {
+		let entries = JsonValue::Object(self.entries.lock().unwrap().clone());
+		std::fs::write(&self.path, entries.to_string())
 	}
 }
 
-/// Write a markdown file with properties from the [ImageProperties] to a specified path.
-/// 
-/// Doesn't make any http connections. Doesn't return an error, but will print any errors it gets to stderr. 
-async fn create_metadata(properties: ImageProperties, path: Arc<PathBuf>) {
-	let metadata_md = format!("# {}\n## {}\n", properties.title, properties.copyright);
-	let file_name = "README.md";
-	let file_path = path.join(file_name);
-
-	match File::create(&file_path) {
-		Ok(mut file) => {
-			match file.write(metadata_md.as_bytes()) {
-				Ok(len) => {
-					if len > metadata_md.as_bytes().len() {
-						eprintln!("Error writing file {:?}: entire file may not have been written", file_path);
-						return
-					}
-				},
-				Err(error) => {
-					eprintln!("Error writing file {:?}: {}", file_path, error);
-					return
-				}
-			};
+/// Key of the [Cache] entry that holds the last image seen for each market
+const LAST_SEEN_KEY: &str = "last-seen";
 
-			match file.sync_all() {
-				Ok(_) => {
-					println!("Successfully written file {:?}", file_path);
-					return
-				},
-				Err(error) => {
-					eprintln!("Error writing file {:?}: {}", file_path, error);
-					return
-				}
-			};
-		},
-		Err(error) => {
-			eprintln!("Error creating file {:?}: {}", file_path, error);
-			return
+/// Headers that make a request conditional on the response having changed since `entry` was cached
+fn conditional_headers(entry: &JsonValue) -> HeaderMap {
+	let mut headers = HeaderMap::new();
+	let validators = [(header::ETAG, header::IF_NONE_MATCH), (header::LAST_MODIFIED, header::IF_MODIFIED_SINCE)];
+	for (validator, condition) in validators {
+		if let Some(value) = entry[validator.as_str()].as_str().and_then(|value| HeaderValue::from_str(value).ok()) {
+			headers.insert(condition, value);
+		}
+	}
+	headers
+}
+
+/// Where the downloaded files end up
+enum Output {
+	/// Loose files in one or more directories, with the same files in each
+	Directories(Vec<PathBuf>),
+	/// Entries in a zip archive
+	Archive(Archive)
+}
+
+impl Output {
+	/// The directory that files are read back from, which is the first one given
+	pub fn directory(&self) -> Option<&PathBuf> {
+		match self {
+			Output::Directories(paths) => paths.first(),
+			Output::Archive(_) => None
+		}
+	}
+}
+
+/// A zip archive that files are added to as they finish downloading
+struct Archive {
+	path: PathBuf,
+	writer: Mutex<ZipWriter<File>>
+}
+
+impl Archive {
+	pub fn create(path: PathBuf) -> std::io::Result<Self> {
+		let writer = Mutex::new(ZipWriter::new(File::create(&path)?));
+		Ok(Self { path, writer })
+	}
+
+	/// Add a file to the archive, returning whether that worked.
+	/// 
+	/// Doesn't return an error, but will print any errors it gets to stderr.
+	pub fn add(&self, name: &str, bytes: &[u8], compression: CompressionMethod) -> bool {
+		let mut writer = self.writer.lock().unwrap();
+		let options = FileOptions::default().compression_method(compression);
+
+		let result = writer.start_file(name, options)
+			.and_then(|_| writer.write_all(bytes).map_err(Into::into));
+		match result {
+			Ok(_) => {
+				status!("Successfully added {} to archive {:?}", name, self.path);
+				true
+			},
+			Err(error) => {
+				eprintln!("Error adding {} to archive {:?}: {}", name, self.path, error);
+				false
+			}
 		}
 	}
+
+	/// Write the archive's central directory. Nothing can be added after this.
+	pub fn finish(&self) -> zip::result::ZipResult<()> {
+		self.writer.lock().unwrap().finish()?;
+		status!("Successfully written archive {:?}", self.path);
+		Ok(())
+	}
 }
 
+/// Resolutions Bing serves the image of the day at
+const SUPPORTED_RESOLUTIONS: [(u16, u16); 21] = [
+	(3840, 2160), (1920, 1200), (1920, 1080), (1366, 768), (1280, 768), (1024, 768),
+	(800, 600), (800, 480), (640, 480), (400, 240), (320, 240),
+	(2160, 3840), (1080, 1920), (768, 1366), (768, 1280), (720, 1280), (600, 800),
+	(480, 800), (480, 640), (240, 400), (240, 320)
+];
+
+/// How many times taller than it is wide a resolution has to be to count as a phone's, see
+/// [Resolution::is_phone_like]. Phone screens are usually between 9:16 (1.78) and 9:21 (2.33)
+const PHONE_ASPECT_RATIO: f64 = 1.5;
+
+/// The largest image Bing serves, its UHD size
+const MAX_RESOLUTION: Resolution = Resolution { x: 3840, y: 2160 };
+
+#[derive(Clone, Copy, PartialEq)]
 struct Resolution {
 	pub x: u16,
 	pub y: u16
@@ -220,14 +3926,419 @@ impl Resolution {
 		Self { x, y }
 	}
 
-	pub fn to_string(&self) -> String {
-		format!("{}x{}", self.x, self.y)
+	/// Number of pixels in an image at this resolution
+	pub fn pixels(&self) -> u64 {
+		u64::from(self.x) * u64::from(self.y)
+	}
+
+	/// Whether Bing serves images at this exact resolution
+	pub fn is_supported(&self) -> bool {
+		SUPPORTED_RESOLUTIONS.contains(&(self.x, self.y))
+	}
+
+	/// This resolution with neither dimension bigger than [MAX_RESOLUTION]'s, which is turned
+	/// sideways for portrait resolutions
+	pub fn capped(&self) -> Resolution {
+		let (max_x, max_y) = if self.y > self.x {
+			(MAX_RESOLUTION.y, MAX_RESOLUTION.x)
+		} else {
+			(MAX_RESOLUTION.x, MAX_RESOLUTION.y)
+		};
+		Resolution::new(self.x.min(max_x), self.y.min(max_y))
+	}
+
+	/// Whether this is at least as wide as it is tall
+	pub fn is_landscape(&self) -> bool {
+		self.x >= self.y
+	}
+
+	/// Whether this is at least [PHONE_ASPECT_RATIO] times as tall as it is wide, like a phone screen
+	pub fn is_phone_like(&self) -> bool {
+		f64::from(self.y) >= f64::from(self.x) * PHONE_ASPECT_RATIO
+	}
+
+	/// The supported resolution closest to this one, by Euclidean distance of the dimensions.
+	/// 
+	/// Ties go to whichever comes first in [SUPPORTED_RESOLUTIONS].
+	pub fn nearest_supported(&self) -> Resolution {
+		self.nearest_supported_where(|_| true)
+	}
+
+	/// The supported resolution closest to this one like [Resolution::nearest_supported], out of only
+	/// those that `filter` accepts. Falls back to all of them if `filter` doesn't accept any.
+	pub fn nearest_supported_where(&self, filter: impl Fn(&Resolution) -> bool) -> Resolution {
+		let distance = |resolution: &Resolution| {
+			let dx = i64::from(resolution.x) - i64::from(self.x);
+			let dy = i64::from(resolution.y) - i64::from(self.y);
+			dx * dx + dy * dy
+		};
+		let supported = SUPPORTED_RESOLUTIONS.iter().copied().map(Resolution::from);
+		supported.clone()
+			.filter(|resolution| filter(resolution))
+			.min_by_key(distance)
+			.or_else(|| supported.min_by_key(distance))
+			.unwrap()
+	}
+}
+
+impl From<(u16, u16)> for Resolution {
+	fn from((x, y): (u16, u16)) -> Self {
+		Self::new(x, y)
+	}
+}
+
+/// Parses resolutions formatted as WIDTHxHEIGHT.
+impl std::str::FromStr for Resolution {
+	type Err = ParseResolutionError;
+
+	fn from_str(value: &str) -> Result<Self, Self::Err> {
+		let (x, y) = value.split_once('x').ok_or(ParseResolutionError)?;
+		match (x.parse::<u16>(), y.parse::<u16>()) {
+			(Ok(x), Ok(y)) => Ok(Self::new(x, y)),
+			_ => Err(ParseResolutionError)
+		}
+	}
+}
+
+/// Same as parsing with [FromStr](std::str::FromStr).
+impl TryFrom<&str> for Resolution {
+	type Error = ParseResolutionError;
+
+	fn try_from(value: &str) -> Result<Self, Self::Error> {
+		value.parse()
+	}
+}
+
+/// Error from parsing a [Resolution] that isn't formatted as WIDTHxHEIGHT
+#[derive(Debug)]
+struct ParseResolutionError;
+
+impl std::fmt::Display for ParseResolutionError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(f, "resolution must be formatted as WIDTHxHEIGHT, ex. 1920x1080")
+	}
+}
+
+impl std::error::Error for ParseResolutionError {}
+
+impl std::fmt::Display for Resolution {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(f, "{}x{}", self.x, self.y)
+	}
+}
+
+/// Serialized as a WIDTHxHEIGHT string, the same as it's written on the command line.
+impl Serialize for Resolution {
+	fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		serializer.collect_str(self)
+	}
+}
+
+impl<'de> Deserialize<'de> for Resolution {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		let value = String::deserialize(deserializer)?;
+		value.parse().map_err(serde::de::Error::custom)
 	}
 }
 
+#[derive(Clone)]
 struct ImageProperties {
 	pub resolution: Arc<Resolution>,
 	pub url: Arc<String>,
+	/// The URL without a resolution or extension, if the API has one
+	pub urlbase: Option<Arc<String>>,
 	pub title: Arc<String>,
-	pub copyright: Arc<String>
-}
\ No newline at end of file
+	/// Left out of the metadata if the API doesn't have one
+	pub copyright: Option<Arc<String>>,
+	/// Date the image is for, already formatted with --date-format
+	pub date: Arc<String>,
+	/// Whichever of [EXTRA_FIELDS] the API returned
+	pub extra: Arc<serde_json::Map<String, JsonValue>>
+}
+
+impl ImageProperties {
+	/// Short hash identifying the image, for {urlhash}. It's of the API's urlbase without the
+	/// market it's from (ex. `/th?id=OHR.Example` from `/th?id=OHR.Example_EN-US1234567890`), so
+	/// it's the same every day and in every market that has the image, or of the URL if there's
+	/// no urlbase
+	pub fn url_hash(&self) -> String {
+		let id = match &self.urlbase {
+			Some(urlbase) => urlbase.rsplit_once('_')
+				.filter(|(_, suffix)| suffix.contains('-'))
+				.map_or(urlbase.as_str(), |(id, _)| id),
+			None => self.url.as_str()
+		};
+		let mut hash = sha256_hex(id.as_bytes());
+		hash.truncate(URL_HASH_LENGTH);
+		hash
+	}
+
+	/// URLs to download the image from at each of `resolutions`, see [image_url]
+	pub fn urls(&self, resolutions: &[Arc<Resolution>], config: &Config) -> Vec<String> {
+		resolutions.iter()
+			.map(|resolution| image_url(&self.url, self.urlbase.as_deref().map(String::as_str), resolution, config))
+			.collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn build_image_url_replaces_resolution() {
+		let url = build_image_url("/th?id=OHR.Example_1920x1080.jpg&pid=hp", &Resolution::new(1366, 768), "1920x1080");
+		assert_eq!(url, "https://bing.com/th?id=OHR.Example_1366x768.jpg&pid=hp");
+	}
+
+	#[test]
+	fn build_image_url_without_token_is_unchanged() {
+		let url = build_image_url("/th?id=OHR.Example_UHD.jpg&pid=hp", &Resolution::new(1366, 768), "1920x1080");
+		assert_eq!(url, "https://bing.com/th?id=OHR.Example_UHD.jpg&pid=hp");
+	}
+
+	#[test]
+	fn build_image_url_replaces_every_token() {
+		let url = build_image_url("/th?id=OHR.Example_1920x1080.jpg&rf=LaDigue_1920x1080.jpg&pid=hp", &Resolution::new(1366, 768), "1920x1080");
+		assert_eq!(url, "https://bing.com/th?id=OHR.Example_1366x768.jpg&rf=LaDigue_1366x768.jpg&pid=hp");
+	}
+
+	#[test]
+	fn build_image_url_with_custom_token() {
+		let url = build_image_url("/th?id=OHR.Example_1080p.jpg&pid=hp", &Resolution::new(1366, 768), "1080p");
+		assert_eq!(url, "https://bing.com/th?id=OHR.Example_1366x768.jpg&pid=hp");
+	}
+
+	#[test]
+	fn resolution_serde_round_trip() {
+		let resolution = Resolution::new(1920, 1080);
+		let json = serde_json::to_string(&resolution).unwrap();
+		assert_eq!(json, "\"1920x1080\"");
+		assert!(serde_json::from_str::<Resolution>(&json).unwrap() == resolution);
+	}
+
+	#[test]
+	fn resolution_deserialize_rejects_invalid() {
+		assert!(serde_json::from_str::<Resolution>("\"1920by1080\"").is_err());
+	}
+
+	#[test]
+	fn resolution_from_tuple() {
+		assert_eq!(Resolution::from((1920, 1080)).to_string(), "1920x1080");
+	}
+
+	#[test]
+	fn resolution_from_str() {
+		let resolution: Resolution = "1366x768".parse().unwrap();
+		assert_eq!((resolution.x, resolution.y), (1366, 768));
+		assert!("1366".parse::<Resolution>().is_err());
+		assert!("1366x".parse::<Resolution>().is_err());
+		assert!("99999x768".parse::<Resolution>().is_err());
+	}
+
+	#[test]
+	fn resolution_try_from_str() {
+		assert_eq!(Resolution::try_from("1920x1200").unwrap().to_string(), "1920x1200");
+		assert!(Resolution::try_from("1920*1200").is_err());
+	}
+
+	#[test]
+	fn max_resolution_is_supported() {
+		assert!(MAX_RESOLUTION.nearest_supported() == MAX_RESOLUTION);
+		let portrait = Resolution::new(MAX_RESOLUTION.y, MAX_RESOLUTION.x);
+		assert!(portrait.nearest_supported() == portrait);
+	}
+
+	#[test]
+	fn nearest_supported_exact_match() {
+		assert!(Resolution::new(1366, 768).nearest_supported() == Resolution::new(1366, 768));
+	}
+
+	#[test]
+	fn nearest_supported_between_sizes() {
+		assert_eq!(Resolution::new(1920, 1081).nearest_supported().to_string(), "1920x1080");
+		assert_eq!(Resolution::new(1920, 1150).nearest_supported().to_string(), "1920x1200");
+	}
+
+	#[test]
+	fn nearest_supported_portrait() {
+		assert_eq!(Resolution::new(1080, 2000).nearest_supported().to_string(), "1080x1920");
+		assert_eq!(Resolution::new(700, 1300).nearest_supported().to_string(), "720x1280");
+	}
+
+	#[test]
+	fn nearest_supported_where_falls_back_when_nothing_matches() {
+		let resolution = Resolution::new(1000, 700);
+		assert_eq!(resolution.nearest_supported_where(|_| false).to_string(), "1024x768");
+		assert_eq!(resolution.nearest_supported_where(|resolution| resolution.y > resolution.x).to_string(), "600x800");
+	}
+
+	/// Writer that accepts at most `chunk` bytes per call to `write` and `capacity` bytes in total,
+	/// like a slow pipe or a disk that fills up
+	struct ShortWriter {
+		chunk: usize,
+		capacity: usize,
+		written: Vec<u8>,
+		calls: usize
+	}
+
+	impl ShortWriter {
+		fn new(chunk: usize, capacity: usize) -> Self {
+			ShortWriter { chunk, capacity, written: Vec::new(), calls: 0 }
+		}
+	}
+
+	impl Write for ShortWriter {
+		fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+			let len = bytes.len().min(self.chunk).min(self.capacity - self.written.len());
+			self.written.extend_from_slice(&bytes[..len]);
+			self.calls += 1;
+			Ok(len)
+		}
+
+		fn flush(&mut self) -> std::io::Result<()> {
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn write_fully_finishes_short_writes() {
+		let bytes: Vec<u8> = (0..100).collect();
+		let mut writer = ShortWriter::new(7, usize::MAX);
+		write_fully(&mut writer, &bytes).unwrap();
+		assert_eq!(writer.written, bytes);
+		assert_eq!(writer.calls, 15);
+	}
+
+	#[test]
+	fn write_fully_reports_writes_that_stop() {
+		let mut writer = ShortWriter::new(7, 10);
+		let error = write_fully(&mut writer, &[0; 100]).unwrap_err();
+		assert_eq!(error.kind(), std::io::ErrorKind::WriteZero);
+		assert_eq!(error.to_string(), "only wrote 10 of 100 bytes");
+		assert_eq!(writer.written.len(), 10);
+	}
+
+	#[test]
+	fn write_atomically_leaves_no_temp_files() {
+		let dir = std::env::temp_dir().join(format!("bingimage-test-{}", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		let file_path = dir.join("1920x1080.jpg");
+		write_atomically(&file_path, false, None, b"image").unwrap();
+		write_atomically(&file_path, false, None, b"new image").unwrap();
+		assert_eq!(std::fs::read(&file_path).unwrap(), b"new image");
+		let names: Vec<_> = std::fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+		assert_eq!(names, ["1920x1080.jpg"]);
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn null_title_and_copyright() {
+		let json: JsonValue = serde_json::from_str(r#"{"images":[{"url":"/th?id=OHR.Example_1920x1080.jpg","urlbase":"/th?id=OHR.Example","title":null,"copyright":null,"startdate":"20261013"}]}"#).unwrap();
+		let (title, copyright) = image_text(&json["images"][0]);
+		assert_eq!(*title, "Untitled");
+		assert!(copyright.is_none());
+		let metadata_md = render_metadata(&title, copyright.as_deref().map(String::as_str));
+		assert!(metadata_md.contains("# Untitled\n"));
+		assert!(!metadata_md.contains("null"));
+	}
+
+	#[test]
+	fn malformed_image_urls() {
+		let json: JsonValue = serde_json::from_str(r#"{"images":[
+			{"url":"th?id=OHR.Example_1920x1080.jpg","urlbase":"OHR.Example"},
+			{"url":"","urlbase":""},
+			{"url":"https://","urlbase":"https:///th?id=OHR.Example"},
+			{"url":"/th?id=OHR.Example_1920x1080.jpg","urlbase":"/th?id=OHR.Example"}
+		]}"#).unwrap();
+		let images = json["images"].as_array().unwrap();
+		for image in &images[..3] {
+			assert!(!is_valid_image_url(image["url"].as_str().unwrap()));
+			assert!(image_urlbase(image).is_none());
+		}
+		assert!(is_valid_image_url(images[3]["url"].as_str().unwrap()));
+		assert_eq!(image_urlbase(&images[3]), Some("/th?id=OHR.Example"));
+		assert!(image_urlbase(&serde_json::json!({ "url": "/th?id=OHR.Example_1920x1080.jpg" })).is_none());
+	}
+
+	#[test]
+	fn market_codes() {
+		assert!(MARKETS.iter().all(|(code, _)| is_valid_market(code)));
+		for market in ["../../x", "en-US/..", "a&foo=b", "en-US&foo=b", "en", "en-", "-US", "en_US", "en-USA"] {
+			assert!(!is_valid_market(market), "{}", market);
+		}
+	}
+
+	#[test]
+	fn rendered_file_names() {
+		for format in ["%Y-%m-%d", "%Y%m%d", "%d.%m.%Y", "%b %e"] {
+			assert!(is_valid_date_format(format), "{}", format);
+		}
+		for format in ["", ".", "..", "%D", "%Y/%m", "%Q"] {
+			assert!(!is_valid_date_format(format), "{}", format);
+		}
+
+		let resolution = Resolution::new(1920, 1080);
+		let date = NaiveDate::from_ymd_opt(2024, 3, 9).unwrap();
+		assert!(is_valid_template("{date}/{resolution}.jpg"));
+		assert_eq!(render_template("{year}/{month}/{date}-{resolution}.jpg", &resolution, date, "2024-03-09", "abc"), "2024/03/2024-03-09-1920x1080.jpg");
+		assert!(is_valid_file_name("2024/03/1920x1080.jpg"));
+		for date_text in ["", ".", ".."] {
+			let file_name = render_template("{date}/{resolution}.jpg", &resolution, date, date_text, "abc");
+			assert!(!is_valid_file_name(&file_name), "{}", file_name);
+		}
+		assert!(!is_valid_file_name("a\\b.jpg"));
+	}
+
+	#[test]
+	fn temp_names() {
+		let temp = temp_path(Path::new("/images/1920x1080.jpg"), None);
+		assert_eq!(temp.parent(), Some(Path::new("/images")));
+		assert!(is_temp_name(temp.file_name().unwrap().to_str().unwrap()));
+		assert!(is_temp_name(temp_path(Path::new("README.md"), Some(Path::new("/tmp"))).file_name().unwrap().to_str().unwrap()));
+		assert_ne!(temp_path(Path::new("a.jpg"), None), temp_path(Path::new("a.jpg"), None));
+		for name in [".1920x1080.jpg.tmp", ".vimrc.tmp", "a.bingimage-1-2.tmp", ".a.bingimage-.tmp", ".a.bingimage-1-.tmp", ".a.bingimage-x-2.tmp", ".a.bingimage-1-2.tmp.bak"] {
+			assert!(!is_temp_name(name), "{}", name);
+		}
+	}
+
+	/// A small image with some detail in it, for encoding
+	fn test_image() -> image::DynamicImage {
+		image::DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 48, |x, y| image::Rgb([(x * 4) as u8, (y * 5) as u8, ((x + y) * 2) as u8])))
+	}
+
+	/// Whether a JPEG has a start of frame marker for a progressive image
+	fn is_progressive(jpeg: &[u8]) -> bool {
+		jpeg.windows(2).any(|marker| marker == [0xff, 0xc2])
+	}
+
+	#[test]
+	fn encode_jpeg_progressive() {
+		let baseline = encode_jpeg(&test_image(), false, None).unwrap();
+		let progressive = encode_jpeg(&test_image(), true, None).unwrap();
+		assert!(!is_progressive(&baseline));
+		assert!(is_progressive(&progressive));
+		for jpeg in [baseline, progressive] {
+			let decoded = image::load_from_memory(&jpeg).unwrap();
+			assert_eq!((decoded.width(), decoded.height()), (64, 48));
+		}
+	}
+
+	/// The sampling factors of a JPEG's luma component, from its start of frame marker, ex. 0x22
+	/// for 4:2:0
+	fn luma_sampling(jpeg: &[u8]) -> u8 {
+		let start = jpeg.windows(2).position(|marker| marker == [0xff, 0xc0] || marker == [0xff, 0xc2]).unwrap();
+		// Marker, length, precision, height, width, component count, then the first component's ID
+		jpeg[start + 11]
+	}
+
+	#[test]
+	fn encode_jpeg_chroma() {
+		let jpeg = |chroma| encode_jpeg(&test_image(), false, chroma).unwrap();
+		assert_eq!(luma_sampling(&jpeg(None)), 0x11);
+		assert_eq!(luma_sampling(&jpeg(Some("444"))), 0x11);
+		assert_eq!(luma_sampling(&jpeg(Some("422"))), 0x21);
+		assert_eq!(luma_sampling(&jpeg(Some("420"))), 0x22);
+		assert!(image::load_from_memory(&jpeg(Some("420"))).is_ok());
+	}
+}